@@ -0,0 +1,365 @@
+extern crate llvm_sys as llvm;
+use crate::codegen::*;
+use llvm::LLVMTypeKind;
+use llvm::core::*;
+use llvm::prelude::LLVMAttributeRef;
+use llvm::prelude::LLVMTypeRef;
+use llvm::prelude::LLVMValueRef;
+use llvm::target::LLVMTargetDataRef;
+use llvm::target::{LLVMABISizeOfType, LLVMGetModuleDataLayout, LLVMOffsetOfElement};
+use llvm::target_machine::LLVMGetDefaultTargetTriple;
+use std::ffi::CStr;
+
+// Function signatures are lowered to the C calling convention of x86-64 System V, so that
+// functions taking or returning structs can be called from C and C++ hosts.
+// Structs larger than 16 bytes are passed as a pointer to a copy (byval) and returned through
+// a pointer to memory of the caller (sret). Smaller structs are passed in one register for
+// each 8 bytes, a floating point register if these bytes only hold doubles and an integer
+// register otherwise. Bools are zero extended to a byte.
+// Other targets, like Windows or ARM, have other rules. There structs are passed as LLVM
+// aggregates, which works between the functions of the program but not with C.
+
+const EIGHTBYTE: u64 = 8;
+const MAX_REGISTER_STRUCT_SIZE: u64 = 2 * EIGHTBYTE;
+
+fn is_struct(ty: LLVMTypeRef) -> bool {
+    unsafe { LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMStructTypeKind }
+}
+
+// the object files are emitted for the default target triple
+pub fn is_system_v_target() -> bool {
+    unsafe {
+        let triple = LLVMGetDefaultTargetTriple();
+        let name = CStr::from_ptr(triple).to_string_lossy().into_owned();
+        LLVMDisposeMessage(triple);
+        name.starts_with("x86_64") && !name.contains("windows") && !name.contains("mingw")
+    }
+}
+
+fn data_layout(codegen_context: &CodeGenContext) -> LLVMTargetDataRef {
+    unsafe { LLVMGetModuleDataLayout(codegen_context.module) }
+}
+
+pub fn is_passed_in_memory(codegen_context: &CodeGenContext, ty: LLVMTypeRef) -> bool {
+    unsafe {
+        codegen_context.lowers_structs
+            && is_struct(ty)
+            && LLVMABISizeOfType(data_layout(codegen_context), ty) > MAX_REGISTER_STRUCT_SIZE
+    }
+}
+
+// marks the eightbytes that hold anything else than doubles
+fn classify(
+    codegen_context: &CodeGenContext,
+    ty: LLVMTypeRef,
+    offset: u64,
+    only_doubles: &mut [bool],
+) {
+    unsafe {
+        if is_struct(ty) {
+            for i in 0..LLVMCountStructElementTypes(ty) {
+                let field_offset = LLVMOffsetOfElement(data_layout(codegen_context), ty, i);
+                let field_t = LLVMStructGetTypeAtIndex(ty, i);
+                classify(
+                    codegen_context,
+                    field_t,
+                    offset + field_offset,
+                    only_doubles,
+                );
+            }
+        } else if LLVMGetTypeKind(ty) != LLVMTypeKind::LLVMDoubleTypeKind {
+            only_doubles[(offset / EIGHTBYTE) as usize] = false;
+        }
+    }
+}
+
+// literal struct of the registers a struct of up to 16 bytes is passed in
+fn register_type(codegen_context: &CodeGenContext, ty: LLVMTypeRef) -> Option<LLVMTypeRef> {
+    if !codegen_context.lowers_structs || !is_struct(ty) || is_passed_in_memory(codegen_context, ty)
+    {
+        return None;
+    }
+    unsafe {
+        let size = LLVMABISizeOfType(data_layout(codegen_context), ty);
+        let mut only_doubles = vec![true; size.div_ceil(EIGHTBYTE) as usize];
+        classify(codegen_context, ty, 0, &mut only_doubles);
+        let mut registers_t: Vec<LLVMTypeRef> = only_doubles
+            .iter()
+            .enumerate()
+            .map(|(i, only_doubles)| {
+                if *only_doubles {
+                    LLVMDoubleTypeInContext(codegen_context.context)
+                } else {
+                    // the last register only holds the remaining bytes
+                    let bytes = EIGHTBYTE.min(size - i as u64 * EIGHTBYTE);
+                    LLVMIntTypeInContext(codegen_context.context, (bytes * 8) as u32)
+                }
+            })
+            .collect();
+        Some(LLVMStructTypeInContext(
+            codegen_context.context,
+            registers_t.as_mut_ptr(),
+            registers_t.len() as u32,
+            false as i32,
+        ))
+    }
+}
+
+// reinterprets the bytes of a value as another type by storing and loading it
+fn build_coercion(
+    codegen_context: &mut CodeGenContext,
+    value: LLVMValueRef,
+    ty: LLVMTypeRef,
+) -> LLVMValueRef {
+    unsafe {
+        let value_t = LLVMTypeOf(value);
+        let layout = data_layout(codegen_context);
+        let slot_t = if LLVMABISizeOfType(layout, value_t) >= LLVMABISizeOfType(layout, ty) {
+            value_t
+        } else {
+            ty
+        };
+        let slot = create_entry_block_alloca(codegen_context, "coerce", slot_t);
+        let value_ptr = LLVMBuildPointerCast(
+            codegen_context.ir_builder,
+            slot,
+            LLVMPointerType(value_t, 0),
+            c"coerceptr".as_ptr(),
+        );
+        LLVMBuildStore(codegen_context.ir_builder, value, value_ptr);
+        let ty_ptr = LLVMBuildPointerCast(
+            codegen_context.ir_builder,
+            slot,
+            LLVMPointerType(ty, 0),
+            c"coerceptr".as_ptr(),
+        );
+        LLVMBuildLoad2(codegen_context.ir_builder, ty, ty_ptr, c"coerced".as_ptr())
+    }
+}
+
+fn attribute_kind(name: &str) -> u32 {
+    unsafe { LLVMGetEnumAttributeKindForName(name.as_ptr() as *const i8, name.len()) }
+}
+
+fn type_attribute(
+    codegen_context: &CodeGenContext,
+    name: &str,
+    ty: LLVMTypeRef,
+) -> LLVMAttributeRef {
+    unsafe { LLVMCreateTypeAttribute(codegen_context.context, attribute_kind(name), ty) }
+}
+
+fn enum_attribute(codegen_context: &CodeGenContext, name: &str) -> LLVMAttributeRef {
+    unsafe { LLVMCreateEnumAttribute(codegen_context.context, attribute_kind(name), 0) }
+}
+
+// LLVM function type for the given parameter and return types
+pub fn lower_function_type(
+    codegen_context: &CodeGenContext,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+    is_variadic: bool,
+) -> LLVMTypeRef {
+    unsafe {
+        let mut lowered_params_t = vec![];
+        let mut lowered_return_t = return_t;
+        if is_passed_in_memory(codegen_context, return_t) {
+            lowered_params_t.push(LLVMPointerType(return_t, 0));
+            lowered_return_t = LLVMVoidTypeInContext(codegen_context.context);
+        } else if let Some(registers_t) = register_type(codegen_context, return_t) {
+            lowered_return_t = registers_t;
+        }
+        for param_t in params_t {
+            if is_passed_in_memory(codegen_context, *param_t) {
+                lowered_params_t.push(LLVMPointerType(*param_t, 0));
+            } else if let Some(registers_t) = register_type(codegen_context, *param_t) {
+                lowered_params_t.push(registers_t);
+            } else {
+                lowered_params_t.push(*param_t);
+            }
+        }
+        LLVMFunctionType(
+            lowered_return_t,
+            lowered_params_t.as_mut_ptr(),
+            lowered_params_t.len() as u32,
+            is_variadic as i32,
+        )
+    }
+}
+
+// adds the attributes of the calling convention to a function or a call
+pub fn add_abi_attributes(
+    codegen_context: &CodeGenContext,
+    function_or_call: LLVMValueRef,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+    is_call: bool,
+) {
+    unsafe {
+        let add_attribute = |index: u32, attribute: LLVMAttributeRef| {
+            if is_call {
+                LLVMAddCallSiteAttribute(function_or_call, index, attribute);
+            } else {
+                LLVMAddAttributeAtIndex(function_or_call, index, attribute);
+            }
+        };
+        let bool_t = bool_type(codegen_context);
+        // index 0 is the return value, parameters start at 1
+        if return_t == bool_t {
+            add_attribute(0, enum_attribute(codegen_context, "zeroext"));
+        }
+        let mut index = 1;
+        if is_passed_in_memory(codegen_context, return_t) {
+            add_attribute(index, type_attribute(codegen_context, "sret", return_t));
+            index += 1;
+        }
+        for param_t in params_t {
+            if is_passed_in_memory(codegen_context, *param_t) {
+                add_attribute(index, type_attribute(codegen_context, "byval", *param_t));
+            } else if *param_t == bool_t {
+                add_attribute(index, enum_attribute(codegen_context, "zeroext"));
+            }
+            index += 1;
+        }
+    }
+}
+
+// parameter and return types of a function before they were lowered
+pub fn function_signature(
+    codegen_context: &CodeGenContext,
+    function: LLVMValueRef,
+) -> (Vec<LLVMTypeRef>, LLVMTypeRef) {
+    unsafe {
+        let ft = match codegen_context.function_types.get(&value_name(function)) {
+            Some(ft) => *ft,
+            // runtime functions are not lowered
+            None => LLVMGlobalGetValueType(function),
+        };
+        let mut params_t: Vec<LLVMTypeRef> =
+            vec![std::ptr::null_mut(); LLVMCountParamTypes(ft) as usize];
+        LLVMGetParamTypes(ft, params_t.as_mut_ptr());
+        (params_t, LLVMGetReturnType(ft))
+    }
+}
+
+// value of a parameter of the given type of the current function
+pub fn build_param_value(
+    codegen_context: &mut CodeGenContext,
+    param: LLVMValueRef,
+    ty: LLVMTypeRef,
+) -> LLVMValueRef {
+    unsafe {
+        if is_passed_in_memory(codegen_context, ty) {
+            LLVMBuildLoad2(codegen_context.ir_builder, ty, param, c"byval".as_ptr())
+        } else if register_type(codegen_context, ty).is_some() {
+            build_coercion(codegen_context, param, ty)
+        } else {
+            param
+        }
+    }
+}
+
+// returns the value from the current function, whose first parameter is the sret pointer
+// if the value is returned in memory
+pub fn build_return(codegen_context: &mut CodeGenContext, value: LLVMValueRef) {
+    unsafe {
+        let value_t = LLVMTypeOf(value);
+        if is_passed_in_memory(codegen_context, value_t) {
+            let function = codegen_context.current_function.unwrap();
+            LLVMBuildStore(codegen_context.ir_builder, value, LLVMGetParam(function, 0));
+            LLVMBuildRetVoid(codegen_context.ir_builder);
+        } else if let Some(registers_t) = register_type(codegen_context, value_t) {
+            let registers = build_coercion(codegen_context, value, registers_t);
+            LLVMBuildRet(codegen_context.ir_builder, registers);
+        } else {
+            LLVMBuildRet(codegen_context.ir_builder, value);
+        }
+    }
+}
+
+// call of a function with arguments of the types of its signature
+pub fn build_lowered_call(
+    codegen_context: &mut CodeGenContext,
+    function: LLVMValueRef,
+    args: &[LLVMValueRef],
+    name: &std::ffi::CStr,
+) -> LLVMValueRef {
+    let (params_t, return_t) = function_signature(codegen_context, function);
+    let ft = unsafe { LLVMGlobalGetValueType(function) };
+    build_call_of_type(
+        codegen_context,
+        function,
+        ft,
+        &params_t,
+        return_t,
+        args,
+        name,
+    )
+}
+
+// call through a pointer to a function with the given parameter and return types
+pub fn build_lowered_indirect_call(
+    codegen_context: &mut CodeGenContext,
+    pointer: LLVMValueRef,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+    args: &[LLVMValueRef],
+    name: &std::ffi::CStr,
+) -> LLVMValueRef {
+    let ft = lower_function_type(codegen_context, params_t, return_t, false);
+    build_call_of_type(codegen_context, pointer, ft, params_t, return_t, args, name)
+}
+
+fn build_call_of_type(
+    codegen_context: &mut CodeGenContext,
+    callee: LLVMValueRef,
+    ft: LLVMTypeRef,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+    args: &[LLVMValueRef],
+    name: &std::ffi::CStr,
+) -> LLVMValueRef {
+    unsafe {
+        let mut args_v = vec![];
+        let return_slot = if is_passed_in_memory(codegen_context, return_t) {
+            let slot = create_entry_block_alloca(codegen_context, "sret", return_t);
+            args_v.push(slot);
+            Some(slot)
+        } else {
+            None
+        };
+        for arg in args.iter() {
+            let arg_t = LLVMTypeOf(*arg);
+            if is_passed_in_memory(codegen_context, arg_t) {
+                // the callee gets a copy on the stack of the caller
+                let copy = create_entry_block_alloca(codegen_context, "byval", arg_t);
+                LLVMBuildStore(codegen_context.ir_builder, *arg, copy);
+                args_v.push(copy);
+            } else if let Some(registers_t) = register_type(codegen_context, arg_t) {
+                args_v.push(build_coercion(codegen_context, *arg, registers_t));
+            } else {
+                args_v.push(*arg);
+            }
+        }
+
+        // calls without a return value must not be named
+        let is_void = LLVMGetTypeKind(LLVMGetReturnType(ft)) == LLVMTypeKind::LLVMVoidTypeKind;
+        let call = LLVMBuildCall2(
+            codegen_context.ir_builder,
+            ft,
+            callee,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            if is_void { c"".as_ptr() } else { name.as_ptr() },
+        );
+        add_abi_attributes(codegen_context, call, params_t, return_t, true);
+
+        if let Some(slot) = return_slot {
+            LLVMBuildLoad2(codegen_context.ir_builder, return_t, slot, name.as_ptr())
+        } else if register_type(codegen_context, return_t).is_some() {
+            build_coercion(codegen_context, call, return_t)
+        } else {
+            call
+        }
+    }
+}
@@ -1,201 +1,2555 @@
 extern crate llvm_sys as llvm;
-use crate::{codegen::*, logger::*};
+use crate::{
+    abi::*, closure::*, codegen::*, infer::*, lexer::Position, logger::*, runtime::*, types::*,
+};
 use llvm::core::*;
+use llvm::prelude::LLVMBasicBlockRef;
+use llvm::prelude::LLVMTypeRef;
 use llvm::prelude::LLVMValueRef;
 use llvm_sys::analysis::LLVMVerifyFunction;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+const BUILTIN_BINARY_OPS: [&str; 16] = [
+    "+", "-", "*", "/", "%", "<", ">", "<=", ">=", "==", "!=", "&", "|", "^", "<<", ">>",
+];
+const BITWISE_OPS: [&str; 5] = ["&", "|", "^", "<<", ">>"];
+
+// result type of a call of a user-defined function with arguments of the given types
+fn infer_call(
+    inference: &mut Inference,
+    name: &str,
+    args: &[Term],
+    pos: Position,
+) -> Result<Term, String> {
+    let Some((params, ret)) = inference.signature(name) else {
+        return Err(type_error(pos, format!("Unknown function {}", name)));
+    };
+    // variable arguments are numbers or strings
+    if inference.env.variadic.contains(name) && args.len() > params.len() {
+        for arg in args[params.len()..].iter() {
+            if inference.resolve(arg) != Term::String {
+                inference.expect_number(arg, pos)?;
+            }
+        }
+        return infer_call_of_type(inference, name, &params, ret, &args[..params.len()], pos);
+    }
+    infer_call_of_type(inference, name, &params, ret, args, pos)
+}
+
+fn infer_call_of_type(
+    inference: &mut Inference,
+    name: &str,
+    params: &[Term],
+    ret: Term,
+    args: &[Term],
+    pos: Position,
+) -> Result<Term, String> {
+    if params.len() != args.len() {
+        return Err(type_error(
+            pos,
+            format!(
+                "{} expects {} arguments, found {}",
+                name,
+                params.len(),
+                args.len()
+            ),
+        ));
+    }
+    for (param, arg) in params.iter().zip(args) {
+        inference.coerce(param, arg, pos)?;
+    }
+    Ok(ret)
+}
 
 pub trait Expr {
     // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32);
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String>;
+
+    // type of the expression, unifies the types of its parts with the types they are used as
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String>;
+
+    // pointer to the storage the expression refers to and the type of the stored value,
+    // used as the destination of an assignment
+    fn generate_address(
+        &self,
+        _codegen_context: &mut CodeGenContext,
+    ) -> Result<(LLVMValueRef, LLVMTypeRef), String> {
+        Err(String::from("Destination of '=' must be a variable"))
+    }
+
+    // marks the calls of the named function whose value is the value of the expression,
+    // returns whether there are any
+    fn mark_tail_calls(&self, _name: &str) -> bool {
+        false
+    }
+}
+
+pub struct NumberExprAst {
+    val: f64,
+}
+impl NumberExprAst {
+    pub fn new(val: f64) -> NumberExprAst {
+        NumberExprAst { val }
+    }
+}
+impl Expr for NumberExprAst {
+    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
+    //     treeprinter.add_print_item(self.val.to_string(), depth, indent_lvl);
+    // }
+
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!("Generate number expr {:?}", self.val));
+        unsafe {
+            let ft = LLVMDoubleTypeInContext(codegen_context.context);
+            Ok(LLVMConstReal(ft, self.val))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        // integral literals can be used as ints
+        if self.val.fract() == 0.0 && self.val.abs() <= i32::MAX as f64 {
+            Ok(inference.fresh_number())
+        } else {
+            Ok(Term::Double)
+        }
+    }
+}
+
+pub struct StringExprAst {
+    val: String,
+}
+impl StringExprAst {
+    pub fn new(val: String) -> StringExprAst {
+        StringExprAst { val }
+    }
+}
+impl Expr for StringExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!("Generate string expr {:?}", self.val));
+        Ok(create_global_string(codegen_context, &self.val))
+    }
+
+    fn infer(&self, _inference: &mut Inference) -> Result<Term, String> {
+        Ok(Term::String)
+    }
+}
+
+pub struct BinaryExprAst {
+    op: String,
+    lhs: Box<dyn Expr>,
+    rhs: Box<dyn Expr>,
+    pos: Position,
+}
+impl BinaryExprAst {
+    pub fn new(op: String, lhs: Box<dyn Expr>, rhs: Box<dyn Expr>, pos: Position) -> BinaryExprAst {
+        BinaryExprAst { op, lhs, rhs, pos }
+    }
+}
+impl Expr for BinaryExprAst {
+    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
+    //     treeprinter.add_print_item(self.op.clone(), depth, indent_lvl);
+    //     self.lhs.print(treeprinter, indent_lvl - 1, depth + 1);
+    //     self.rhs.print(treeprinter, indent_lvl + 1, depth + 1);
+    // }
+
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            if self.op == "=" {
+                // assignment stores the value of rhs and evaluates to it, lhs is not evaluated
+                let rhs_value = self.rhs.generate_code(codegen_context)?;
+                let (address, value_t) = self.lhs.generate_address(codegen_context)?;
+                let Some(rhs_value) = build_conversion(codegen_context, rhs_value, value_t) else {
+                    return Err(format!(
+                        "Cannot assign a {} to a {} variable",
+                        type_name(LLVMTypeOf(rhs_value)),
+                        type_name(value_t)
+                    ));
+                };
+                log_verbose(String::from("Generate assignment"));
+                LLVMBuildStore(codegen_context.ir_builder, rhs_value, address);
+                return Ok(rhs_value);
+            }
+
+            let lhs_value = self.lhs.generate_code(codegen_context)?;
+            let rhs_value = self.rhs.generate_code(codegen_context)?;
+
+            log_verbose(format!("Generate binary expr {:?}", self.op));
+            let name = c"op".as_ptr() as *const _;
+
+            // user-defined operators are calls to a function named binary<op>, a definition of
+            // a bitwise operator replaces the built-in one for operands of all types, ints too
+            let function_name = format!("binary{}", self.op);
+            let ptr = (function_name.clone() + "\0").into_bytes();
+            let function = LLVMGetNamedFunction(codegen_context.module, ptr.as_ptr() as *const i8);
+            let is_bitwise = BITWISE_OPS.contains(&self.op.as_str());
+            if !BUILTIN_BINARY_OPS.contains(&self.op.as_str())
+                || (is_bitwise && !function.is_null())
+            {
+                if function.is_null() {
+                    return Err(format!("Invalid binary operator {}", self.op));
+                }
+                let mut args_v = [lhs_value, rhs_value];
+                return build_call(codegen_context, function, &mut args_v, &function_name);
+            }
+            if !is_number(lhs_value) || !is_number(rhs_value) {
+                return Err(format!(
+                    "Operator {} expects numbers, found {} and {}",
+                    self.op,
+                    type_name(LLVMTypeOf(lhs_value)),
+                    type_name(LLVMTypeOf(rhs_value))
+                ));
+            }
+
+            // bools are calculated as ints, bitwise operators also apply to integral constants
+            let mut value_t = common_number_type(codegen_context, lhs_value, rhs_value);
+            if value_t == bool_type(codegen_context)
+                || (is_bitwise && is_integral(lhs_value) && is_integral(rhs_value))
+            {
+                value_t = int_type(codegen_context);
+            }
+            let lhs_value = build_conversion(codegen_context, lhs_value, value_t).unwrap();
+            let rhs_value = build_conversion(codegen_context, rhs_value, value_t).unwrap();
+            let builder = codegen_context.ir_builder;
+
+            if value_t == int_type(codegen_context) {
+                let predicate = match self.op.as_str() {
+                    "+" => return Ok(LLVMBuildAdd(builder, lhs_value, rhs_value, name)),
+                    "-" => return Ok(LLVMBuildSub(builder, lhs_value, rhs_value, name)),
+                    "*" => return Ok(LLVMBuildMul(builder, lhs_value, rhs_value, name)),
+                    "/" => return Ok(LLVMBuildSDiv(builder, lhs_value, rhs_value, name)),
+                    "%" => return Ok(LLVMBuildSRem(builder, lhs_value, rhs_value, name)),
+                    "&" => return Ok(LLVMBuildAnd(builder, lhs_value, rhs_value, name)),
+                    "|" => return Ok(LLVMBuildOr(builder, lhs_value, rhs_value, name)),
+                    "^" => return Ok(LLVMBuildXor(builder, lhs_value, rhs_value, name)),
+                    "<<" => return Ok(LLVMBuildShl(builder, lhs_value, rhs_value, name)),
+                    ">>" => return Ok(LLVMBuildAShr(builder, lhs_value, rhs_value, name)),
+                    "<" => llvm::LLVMIntPredicate::LLVMIntSLT,
+                    ">" => llvm::LLVMIntPredicate::LLVMIntSGT,
+                    "<=" => llvm::LLVMIntPredicate::LLVMIntSLE,
+                    ">=" => llvm::LLVMIntPredicate::LLVMIntSGE,
+                    "==" => llvm::LLVMIntPredicate::LLVMIntEQ,
+                    _ => llvm::LLVMIntPredicate::LLVMIntNE,
+                };
+                let cmp_value =
+                    LLVMBuildICmp(builder, predicate, lhs_value, rhs_value, c"cmptmp".as_ptr());
+                // comparisons evaluate to 0.0 or 1.0
+                return Ok(LLVMBuildUIToFP(
+                    builder,
+                    cmp_value,
+                    LLVMDoubleTypeInContext(codegen_context.context),
+                    c"booltmp".as_ptr(),
+                ));
+            }
+
+            match self.op.as_str() {
+                "+" => Ok(LLVMBuildFAdd(builder, lhs_value, rhs_value, name)),
+                "-" => Ok(LLVMBuildFSub(builder, lhs_value, rhs_value, name)),
+                "*" => Ok(LLVMBuildFMul(builder, lhs_value, rhs_value, name)),
+                "/" => Ok(LLVMBuildFDiv(builder, lhs_value, rhs_value, name)),
+                "%" => Ok(LLVMBuildFRem(builder, lhs_value, rhs_value, name)),
+                _ if is_bitwise => Err(format!(
+                    "Operator {} expects ints, found {} and {}",
+                    self.op,
+                    type_name(LLVMTypeOf(lhs_value)),
+                    type_name(LLVMTypeOf(rhs_value))
+                )),
+                _ => {
+                    let predicate = match self.op.as_str() {
+                        "<" => llvm::LLVMRealPredicate::LLVMRealOLT,
+                        ">" => llvm::LLVMRealPredicate::LLVMRealOGT,
+                        "<=" => llvm::LLVMRealPredicate::LLVMRealOLE,
+                        ">=" => llvm::LLVMRealPredicate::LLVMRealOGE,
+                        "==" => llvm::LLVMRealPredicate::LLVMRealOEQ,
+                        // unordered, so that NaN != NaN holds
+                        _ => llvm::LLVMRealPredicate::LLVMRealUNE,
+                    };
+                    let cmp_value =
+                        LLVMBuildFCmp(builder, predicate, lhs_value, rhs_value, c"cmptmp".as_ptr());
+                    // comparisons evaluate to 0.0 or 1.0
+                    Ok(LLVMBuildUIToFP(
+                        builder,
+                        cmp_value,
+                        LLVMDoubleTypeInContext(codegen_context.context),
+                        c"booltmp".as_ptr(),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let lhs = self.lhs.infer(inference)?;
+        let rhs = self.rhs.infer(inference)?;
+        if self.op == "=" {
+            inference.coerce(&lhs, &rhs, self.pos)?;
+            return Ok(lhs);
+        }
+
+        // like in code generation a definition of a bitwise operator replaces the built-in one
+        let function_name = format!("binary{}", self.op);
+        let is_user_defined = inference.signature(&function_name).is_some();
+        let is_bitwise = BITWISE_OPS.contains(&self.op.as_str());
+        if !BUILTIN_BINARY_OPS.contains(&self.op.as_str()) || (is_bitwise && is_user_defined) {
+            if !is_user_defined {
+                return Err(type_error(
+                    self.pos,
+                    format!("Invalid binary operator {}", self.op),
+                ));
+            }
+            return infer_call(inference, &function_name, &[lhs, rhs], self.pos);
+        }
+        if is_bitwise {
+            inference.expect_int(&lhs, self.pos)?;
+            inference.expect_int(&rhs, self.pos)?;
+            return Ok(Term::Int);
+        }
+
+        inference.expect_number(&lhs, self.pos)?;
+        inference.expect_number(&rhs, self.pos)?;
+        let ty = inference.join(&lhs, &rhs, self.pos)?;
+        if !["+", "-", "*", "/", "%"].contains(&self.op.as_str()) {
+            // comparisons evaluate to 0.0 or 1.0
+            return Ok(Term::Double);
+        }
+        // bools are calculated as ints
+        Ok(if ty == Term::Bool { Term::Int } else { ty })
+    }
+}
+
+// short-circuiting && and ||, the rhs is only evaluated if it decides the result
+pub struct LogicalExprAst {
+    op: String,
+    lhs: Box<dyn Expr>,
+    rhs: Box<dyn Expr>,
+    pos: Position,
+}
+impl LogicalExprAst {
+    pub fn new(
+        op: String,
+        lhs: Box<dyn Expr>,
+        rhs: Box<dyn Expr>,
+        pos: Position,
+    ) -> LogicalExprAst {
+        LogicalExprAst { op, lhs, rhs, pos }
+    }
+}
+impl Expr for LogicalExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let lhs_value = self.lhs.generate_code(codegen_context)?;
+
+            log_verbose(format!("Generate logical expr {:?}", self.op));
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let lhs_value = build_condition(codegen_context, lhs_value, c"lhscond")?;
+
+            let function = codegen_context.current_function.unwrap();
+            let lhs_bb = LLVMGetInsertBlock(codegen_context.ir_builder);
+            let rhs_bb =
+                LLVMAppendBasicBlockInContext(codegen_context.context, function, c"rhs".as_ptr());
+            let merge_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"logiccont".as_ptr());
+
+            // the value of the expression if the rhs is skipped
+            let is_or = self.op == "||";
+            if is_or {
+                LLVMBuildCondBr(codegen_context.ir_builder, lhs_value, merge_bb, rhs_bb);
+            } else {
+                LLVMBuildCondBr(codegen_context.ir_builder, lhs_value, rhs_bb, merge_bb);
+            }
+            let short_circuit_value = LLVMConstInt(
+                LLVMInt1TypeInContext(codegen_context.context),
+                is_or as u64,
+                0,
+            );
+
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, rhs_bb);
+            let rhs_value = self.rhs.generate_code(codegen_context)?;
+            let rhs_value = build_condition(codegen_context, rhs_value, c"rhscond")?;
+            LLVMBuildBr(codegen_context.ir_builder, merge_bb);
+            let rhs_bb = LLVMGetInsertBlock(codegen_context.ir_builder);
+
+            LLVMAppendExistingBasicBlock(function, merge_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, merge_bb);
+            let phi = LLVMBuildPhi(
+                codegen_context.ir_builder,
+                LLVMInt1TypeInContext(codegen_context.context),
+                c"logictmp".as_ptr(),
+            );
+            let mut incoming_values = [short_circuit_value, rhs_value];
+            let mut incoming_blocks = [lhs_bb, rhs_bb];
+            LLVMAddIncoming(
+                phi,
+                incoming_values.as_mut_ptr(),
+                incoming_blocks.as_mut_ptr(),
+                2,
+            );
+            Ok(LLVMBuildUIToFP(
+                codegen_context.ir_builder,
+                phi,
+                dt,
+                c"booltmp".as_ptr(),
+            ))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let lhs = self.lhs.infer(inference)?;
+        inference.expect_number(&lhs, self.pos)?;
+        let rhs = self.rhs.infer(inference)?;
+        inference.expect_number(&rhs, self.pos)?;
+        Ok(Term::Double)
+    }
+}
+
+pub struct UnaryExprAst {
+    op: String,
+    operand: Box<dyn Expr>,
+    pos: Position,
+}
+impl UnaryExprAst {
+    pub fn new(op: String, operand: Box<dyn Expr>, pos: Position) -> UnaryExprAst {
+        UnaryExprAst { op, operand, pos }
+    }
+}
+impl Expr for UnaryExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let operand_value = self.operand.generate_code(codegen_context)?;
+
+            log_verbose(format!("Generate unary expr {:?}", self.op));
+
+            // user-defined operators are calls to a function named unary<op> and take precedence
+            // over the built-in ones
+            let function_name = format!("unary{}", self.op);
+            let ptr = (function_name.clone() + "\0").into_bytes();
+            let function = LLVMGetNamedFunction(codegen_context.module, ptr.as_ptr() as *const i8);
+            if !function.is_null() {
+                let mut args_v = [operand_value];
+                return build_call(codegen_context, function, &mut args_v, &function_name);
+            }
+
+            if !is_number(operand_value) && ["-", "!", "~"].contains(&self.op.as_str()) {
+                return Err(format!(
+                    "Operator {} expects a number, found {}",
+                    self.op,
+                    type_name(LLVMTypeOf(operand_value))
+                ));
+            }
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            match self.op.as_str() {
+                "-" if is_double(operand_value) => Ok(LLVMBuildFNeg(
+                    codegen_context.ir_builder,
+                    operand_value,
+                    c"negtmp".as_ptr(),
+                )),
+                "-" => {
+                    let it = int_type(codegen_context);
+                    let operand_value =
+                        build_conversion(codegen_context, operand_value, it).unwrap();
+                    Ok(LLVMBuildNeg(
+                        codegen_context.ir_builder,
+                        operand_value,
+                        c"negtmp".as_ptr(),
+                    ))
+                }
+                "~" if is_double(operand_value) => Err(String::from("Operator ~ expects an int")),
+                "~" => {
+                    let it = int_type(codegen_context);
+                    let operand_value =
+                        build_conversion(codegen_context, operand_value, it).unwrap();
+                    Ok(LLVMBuildNot(
+                        codegen_context.ir_builder,
+                        operand_value,
+                        c"nottmp".as_ptr(),
+                    ))
+                }
+                "!" => {
+                    // logical not, 1.0 if the operand is zero and 0.0 otherwise
+                    let is_non_zero = build_condition(codegen_context, operand_value, c"nottmp")?;
+                    let is_zero =
+                        LLVMBuildNot(codegen_context.ir_builder, is_non_zero, c"nottmp".as_ptr());
+                    Ok(LLVMBuildUIToFP(
+                        codegen_context.ir_builder,
+                        is_zero,
+                        dt,
+                        c"booltmp".as_ptr(),
+                    ))
+                }
+                _ => Err(format!("Invalid unary operator {}", self.op)),
+            }
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let operand = self.operand.infer(inference)?;
+        let function_name = format!("unary{}", self.op);
+        if inference.signature(&function_name).is_some() {
+            return infer_call(inference, &function_name, &[operand], self.pos);
+        }
+        match self.op.as_str() {
+            "-" => {
+                inference.expect_number(&operand, self.pos)?;
+                match inference.resolve(&operand) {
+                    Term::Bool => Ok(Term::Int),
+                    operand => Ok(operand),
+                }
+            }
+            "~" => {
+                inference.expect_int(&operand, self.pos)?;
+                Ok(Term::Int)
+            }
+            "!" => {
+                inference.expect_number(&operand, self.pos)?;
+                Ok(Term::Double)
+            }
+            _ => Err(type_error(
+                self.pos,
+                format!("Invalid unary operator {}", self.op),
+            )),
+        }
+    }
+}
+
+pub struct VariableExprAst {
+    name: String,
+    pos: Position,
+}
+impl VariableExprAst {
+    pub fn new(name: String, pos: Position) -> VariableExprAst {
+        VariableExprAst { name, pos }
+    }
+}
+impl Expr for VariableExprAst {
+    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
+    //     treeprinter.add_print_item(self.name.clone(), depth, indent_lvl);
+    // }
+
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!("Generate variable expr {:?}", self.name));
+        unsafe {
+            let name = (self.name.clone() + "\0").into_bytes();
+            let ptr = name.as_ptr() as *const i8;
+
+            // locals shadow globals
+            if let Some(alloca) = codegen_context.named_values.get(&self.name) {
+                return Ok(LLVMBuildLoad2(
+                    codegen_context.ir_builder,
+                    LLVMGetAllocatedType(*alloca),
+                    *alloca,
+                    ptr,
+                ));
+            }
+
+            let global = LLVMGetNamedGlobal(codegen_context.module, ptr);
+            if global.is_null() {
+                // the name of a function is a closure without an environment
+                let function = LLVMGetNamedFunction(codegen_context.module, ptr);
+                if !function.is_null() {
+                    return Ok(build_function_closure(codegen_context, function));
+                }
+                return Err(format!("Unknown variable name {}", self.name));
+            }
+            if LLVMIsGlobalConstant(global) != 0 {
+                // constants are replaced by their value
+                return Ok(LLVMGetInitializer(global));
+            }
+            Ok(LLVMBuildLoad2(
+                codegen_context.ir_builder,
+                LLVMGlobalGetValueType(global),
+                global,
+                ptr,
+            ))
+        }
+    }
+
+    fn generate_address(
+        &self,
+        codegen_context: &mut CodeGenContext,
+    ) -> Result<(LLVMValueRef, LLVMTypeRef), String> {
+        unsafe {
+            if let Some(alloca) = codegen_context.named_values.get(&self.name) {
+                return Ok((*alloca, LLVMGetAllocatedType(*alloca)));
+            }
+            let name = (self.name.clone() + "\0").into_bytes();
+            let global = LLVMGetNamedGlobal(codegen_context.module, name.as_ptr() as *const i8);
+            if global.is_null() {
+                Err(format!("Unknown variable name {}", self.name))
+            } else if LLVMIsGlobalConstant(global) != 0 {
+                Err(format!("Cannot assign to constant {}", self.name))
+            } else {
+                Ok((global, LLVMGlobalGetValueType(global)))
+            }
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        // locals shadow globals
+        if let Some(term) = inference.lookup_local(&self.name) {
+            return Ok(term);
+        }
+        if let Some(term) = inference.global(&self.name) {
+            return Ok(term);
+        }
+        match inference.signature(&self.name) {
+            Some((params, ret)) => Ok(Term::Function(params, Box::new(ret))),
+            None => Err(type_error(
+                self.pos,
+                format!("Unknown variable name {}", self.name),
+            )),
+        }
+    }
+}
+
+// array literal [a, b, c]
+pub struct ArrayExprAst {
+    elements: Vec<Box<dyn Expr>>,
+    pos: Position,
+}
+impl ArrayExprAst {
+    pub fn new(elements: Vec<Box<dyn Expr>>, pos: Position) -> ArrayExprAst {
+        ArrayExprAst { elements, pos }
+    }
+}
+impl Expr for ArrayExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!(
+            "Generate array expr with {} elements",
+            self.elements.len()
+        ));
+        unsafe {
+            let i64_t = LLVMInt64TypeInContext(codegen_context.context);
+            let len = LLVMConstInt(i64_t, self.elements.len() as u64, false as i32);
+            let array = build_array_alloc(codegen_context, len);
+            let data =
+                LLVMBuildExtractValue(codegen_context.ir_builder, array, 1, c"data".as_ptr());
+            for (i, element) in self.elements.iter().enumerate() {
+                let value = element.generate_code(codegen_context)?;
+                let dt = LLVMDoubleTypeInContext(codegen_context.context);
+                let Some(value) = build_conversion(codegen_context, value, dt) else {
+                    return Err(format!(
+                        "Array elements must be numbers, found {}",
+                        type_name(LLVMTypeOf(value))
+                    ));
+                };
+                let mut indices = [LLVMConstInt(i64_t, i as u64, false as i32)];
+                let element_ptr = LLVMBuildGEP2(
+                    codegen_context.ir_builder,
+                    LLVMTypeOf(value),
+                    data,
+                    indices.as_mut_ptr(),
+                    1,
+                    c"element".as_ptr(),
+                );
+                LLVMBuildStore(codegen_context.ir_builder, value, element_ptr);
+            }
+            Ok(array)
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        for element in self.elements.iter() {
+            let element = element.infer(inference)?;
+            inference.expect_number(&element, self.pos)?;
+        }
+        Ok(Term::Array)
+    }
+}
+
+// array element access a[i], which can also be assigned to
+pub struct IndexExprAst {
+    array: Box<dyn Expr>,
+    index: Box<dyn Expr>,
+    pos: Position,
+}
+impl IndexExprAst {
+    pub fn new(array: Box<dyn Expr>, index: Box<dyn Expr>, pos: Position) -> IndexExprAst {
+        IndexExprAst { array, index, pos }
+    }
+}
+impl Expr for IndexExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose("Generate index expr".to_string());
+        let (element_ptr, element_t) = self.generate_address(codegen_context)?;
+        unsafe {
+            Ok(LLVMBuildLoad2(
+                codegen_context.ir_builder,
+                element_t,
+                element_ptr,
+                c"element".as_ptr(),
+            ))
+        }
+    }
+
+    fn generate_address(
+        &self,
+        codegen_context: &mut CodeGenContext,
+    ) -> Result<(LLVMValueRef, LLVMTypeRef), String> {
+        let array = self.array.generate_code(codegen_context)?;
+        let index = self.index.generate_code(codegen_context)?;
+        unsafe {
+            if LLVMTypeOf(array) != array_type(codegen_context) {
+                return Err(format!(
+                    "Cannot index a value of type {}",
+                    type_name(LLVMTypeOf(array))
+                ));
+            }
+            if !is_number(index) {
+                return Err(format!(
+                    "Array index must be a number, found {}",
+                    type_name(LLVMTypeOf(index))
+                ));
+            }
+            let double_t = LLVMDoubleTypeInContext(codegen_context.context);
+            let data =
+                LLVMBuildExtractValue(codegen_context.ir_builder, array, 1, c"data".as_ptr());
+            let index = build_size(codegen_context, index, c"index");
+            let len = LLVMBuildExtractValue(codegen_context.ir_builder, array, 0, c"len".as_ptr());
+            build_bounds_check(codegen_context, index, len, self.pos.line);
+            let mut indices = [index];
+            let element_ptr = LLVMBuildGEP2(
+                codegen_context.ir_builder,
+                double_t,
+                data,
+                indices.as_mut_ptr(),
+                1,
+                c"elementptr".as_ptr(),
+            );
+            Ok((element_ptr, double_t))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let array = self.array.infer(inference)?;
+        inference.unify(&Term::Array, &array, self.pos)?;
+        let index = self.index.infer(inference)?;
+        inference.expect_number(&index, self.pos)?;
+        Ok(Term::Double)
+    }
+}
+
+// struct field access p.x, which can also be assigned to
+pub struct FieldExprAst {
+    object: Box<dyn Expr>,
+    field: String,
+    pos: Position,
+}
+impl FieldExprAst {
+    pub fn new(object: Box<dyn Expr>, field: String, pos: Position) -> FieldExprAst {
+        FieldExprAst { object, field, pos }
+    }
+}
+impl FieldExprAst {
+    fn field_index(
+        &self,
+        codegen_context: &CodeGenContext,
+        object_t: LLVMTypeRef,
+    ) -> Result<u32, String> {
+        let Some(definition) = get_struct_definition(codegen_context, object_t) else {
+            return Err(format!(
+                "Cannot access field {} of a value of type {}",
+                self.field,
+                type_name(object_t)
+            ));
+        };
+        match definition
+            .fields
+            .iter()
+            .position(|field| *field == self.field)
+        {
+            Some(index) => Ok(index as u32),
+            None => Err(format!(
+                "Struct {} has no field {}",
+                type_name(object_t),
+                self.field
+            )),
+        }
+    }
 }
+impl Expr for FieldExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!("Generate field expr {:?}", self.field));
+        let object = self.object.generate_code(codegen_context)?;
+        unsafe {
+            let index = self.field_index(codegen_context, LLVMTypeOf(object))?;
+            let name = (self.field.clone() + "\0").into_bytes();
+            Ok(LLVMBuildExtractValue(
+                codegen_context.ir_builder,
+                object,
+                index,
+                name.as_ptr() as *const i8,
+            ))
+        }
+    }
+
+    fn generate_address(
+        &self,
+        codegen_context: &mut CodeGenContext,
+    ) -> Result<(LLVMValueRef, LLVMTypeRef), String> {
+        let (object_ptr, object_t) = self.object.generate_address(codegen_context)?;
+        let index = self.field_index(codegen_context, object_t)?;
+        unsafe {
+            let field_ptr = LLVMBuildStructGEP2(
+                codegen_context.ir_builder,
+                object_t,
+                object_ptr,
+                index,
+                c"fieldptr".as_ptr(),
+            );
+            Ok((field_ptr, LLVMStructGetTypeAtIndex(object_t, index)))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let object = self.object.infer(inference)?;
+        let env = inference.env;
+        let name = match inference.resolve(&object) {
+            Term::Struct(name) => name,
+            Term::Var(_) => {
+                // the struct of an unknown value is known if only one struct has the field
+                let mut candidates = env
+                    .structs
+                    .iter()
+                    .filter(|(_, fields)| fields.iter().any(|(field, _)| *field == self.field));
+                let name = match (candidates.next(), candidates.next()) {
+                    (Some((name, _)), None) => name.clone(),
+                    (None, _) => {
+                        return Err(type_error(
+                            self.pos,
+                            format!("No struct has a field {}", self.field),
+                        ));
+                    }
+                    _ => {
+                        return Err(type_error(
+                            self.pos,
+                            format!(
+                                "Several structs have a field {}, annotate the type",
+                                self.field
+                            ),
+                        ));
+                    }
+                };
+                inference.unify(&Term::Struct(name.clone()), &object, self.pos)?;
+                name
+            }
+            object => {
+                return Err(type_error(
+                    self.pos,
+                    format!(
+                        "Cannot access field {} of a value of type {}",
+                        self.field,
+                        object.name()
+                    ),
+                ));
+            }
+        };
+        let field = env
+            .structs
+            .get(&name)
+            .and_then(|fields| fields.iter().find(|(field, _)| *field == self.field));
+        match field {
+            Some((_, ty)) => Ok(Term::from_type(ty)),
+            None => Err(type_error(
+                self.pos,
+                format!("Struct {} has no field {}", name, self.field),
+            )),
+        }
+    }
+}
+
+// tuple literal (a, b), a literal struct of its elements
+pub struct TupleExprAst {
+    elements: Vec<Box<dyn Expr>>,
+    ty: TypeSlot,
+}
+impl TupleExprAst {
+    pub fn new(elements: Vec<Box<dyn Expr>>) -> TupleExprAst {
+        TupleExprAst {
+            elements,
+            ty: TypeSlot::default(),
+        }
+    }
+}
+impl Expr for TupleExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!(
+            "Generate tuple expr with {} elements",
+            self.elements.len()
+        ));
+        let values = self
+            .elements
+            .iter()
+            .map(|element| element.generate_code(codegen_context))
+            .collect::<Result<Vec<LLVMValueRef>, String>>()?;
+        // the elements are converted to their inferred types
+        let tuple_t = match &*self.ty.borrow() {
+            Some(ty) => ty.llvm_type(codegen_context)?,
+            None => unsafe {
+                let mut elements_t: Vec<LLVMTypeRef> =
+                    values.iter().map(|value| LLVMTypeOf(*value)).collect();
+                LLVMStructTypeInContext(
+                    codegen_context.context,
+                    elements_t.as_mut_ptr(),
+                    elements_t.len() as u32,
+                    false as i32,
+                )
+            },
+        };
+        build_struct(codegen_context, tuple_t, &values, "tuple")
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let elements = self
+            .elements
+            .iter()
+            .map(|element| element.infer(inference))
+            .collect::<Result<Vec<Term>, String>>()?;
+        let tuple = Term::Tuple(elements);
+        inference.defer(tuple.clone(), self.ty.clone());
+        Ok(tuple)
+    }
+}
+
+// element t.0 of a tuple, which can also be assigned to
+pub struct TupleIndexExprAst {
+    tuple: Box<dyn Expr>,
+    index: usize,
+    // number of elements of a destructured tuple, which determines the type of an unknown value
+    arity: Option<usize>,
+    pos: Position,
+}
+impl TupleIndexExprAst {
+    pub fn new(
+        tuple: Box<dyn Expr>,
+        index: usize,
+        arity: Option<usize>,
+        pos: Position,
+    ) -> TupleIndexExprAst {
+        TupleIndexExprAst {
+            tuple,
+            index,
+            arity,
+            pos,
+        }
+    }
+
+    fn check_index(&self, tuple_t: LLVMTypeRef) -> Result<u32, String> {
+        unsafe {
+            if LLVMGetTypeKind(tuple_t) != llvm::LLVMTypeKind::LLVMStructTypeKind
+                || LLVMIsLiteralStruct(tuple_t) == 0
+                || self.index >= LLVMCountStructElementTypes(tuple_t) as usize
+            {
+                return Err(format!(
+                    "Cannot access element {} of a value of type {}",
+                    self.index,
+                    type_name(tuple_t)
+                ));
+            }
+        }
+        Ok(self.index as u32)
+    }
+}
+impl Expr for TupleIndexExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!("Generate tuple element {}", self.index));
+        let tuple = self.tuple.generate_code(codegen_context)?;
+        unsafe {
+            let index = self.check_index(LLVMTypeOf(tuple))?;
+            Ok(LLVMBuildExtractValue(
+                codegen_context.ir_builder,
+                tuple,
+                index,
+                c"element".as_ptr(),
+            ))
+        }
+    }
+
+    fn generate_address(
+        &self,
+        codegen_context: &mut CodeGenContext,
+    ) -> Result<(LLVMValueRef, LLVMTypeRef), String> {
+        let (tuple_ptr, tuple_t) = self.tuple.generate_address(codegen_context)?;
+        let index = self.check_index(tuple_t)?;
+        unsafe {
+            let element_ptr = LLVMBuildStructGEP2(
+                codegen_context.ir_builder,
+                tuple_t,
+                tuple_ptr,
+                index,
+                c"elementptr".as_ptr(),
+            );
+            Ok((element_ptr, LLVMStructGetTypeAtIndex(tuple_t, index)))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let tuple = self.tuple.infer(inference)?;
+        let elements = match (inference.resolve(&tuple), self.arity) {
+            (Term::Tuple(elements), _) => elements,
+            (Term::Var(_), Some(arity)) => {
+                let elements: Vec<Term> = (0..arity).map(|_| inference.fresh()).collect();
+                inference.unify(&Term::Tuple(elements.clone()), &tuple, self.pos)?;
+                elements
+            }
+            (Term::Var(_), None) => {
+                return Err(type_error(
+                    self.pos,
+                    format!(
+                        "Cannot access element {} of a value of unknown type, annotate the tuple type",
+                        self.index
+                    ),
+                ));
+            }
+            (tuple, _) => {
+                return Err(type_error(
+                    self.pos,
+                    format!(
+                        "Cannot access element {} of a value of type {}",
+                        self.index,
+                        tuple.name()
+                    ),
+                ));
+            }
+        };
+        if let Some(arity) = self.arity
+            && arity != elements.len()
+        {
+            return Err(type_error(
+                self.pos,
+                format!(
+                    "Cannot destructure a tuple of {} elements into {} variables",
+                    elements.len(),
+                    arity
+                ),
+            ));
+        }
+        match elements.get(self.index) {
+            Some(element) => Ok(element.clone()),
+            None => Err(type_error(
+                self.pos,
+                format!(
+                    "Tuple of {} elements has no element {}",
+                    elements.len(),
+                    self.index
+                ),
+            )),
+        }
+    }
+}
+
+pub struct FunctionCallExprAst {
+    pub callee: String,
+    pub args: Vec<Box<dyn Expr>>,
+    pos: Position,
+    // type of the function value if the callee is a variable
+    callee_type: TypeSlot,
+    // recursive call whose value is returned by the function
+    is_tail_call: Cell<bool>,
+}
+impl FunctionCallExprAst {
+    pub fn new(callee: String, args: Vec<Box<dyn Expr>>, pos: Position) -> FunctionCallExprAst {
+        FunctionCallExprAst {
+            callee,
+            args,
+            pos,
+            callee_type: TypeSlot::default(),
+            is_tail_call: Cell::new(false),
+        }
+    }
+}
+impl Expr for FunctionCallExprAst {
+    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
+    //     // TODO args
+    //     treeprinter.add_print_item(self.callee.clone(), depth, indent_lvl);
+    // }
+
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            // convert rust *const u8 pointer to C-compatible *const i8 pointer
+            let name = (self.callee.clone() + "\0").into_bytes();
+            let ptr = name.as_ptr() as *const i8;
+            let callee_nf = LLVMGetNamedFunction(codegen_context.module, ptr);
+            let args_v: Vec<Result<LLVMValueRef, String>> = self
+                .args
+                .iter()
+                .map(|expr| expr.generate_code(codegen_context))
+                .collect();
+            for arg in args_v.iter() {
+                match arg {
+                    Ok(_) => {}
+                    Err(e) => {
+                        return Err(e.clone());
+                    }
+                };
+            }
+            let mut args_v: Vec<LLVMValueRef> =
+                args_v.into_iter().map(|val| val.unwrap()).collect();
+
+            // variables holding a function are called indirectly, they shadow functions
+            if let Some(Type::Function(params, ret)) = &*self.callee_type.borrow() {
+                log_verbose(format!("Generate indirect call {:?}", self.callee));
+                let closure = VariableExprAst::new(self.callee.clone(), self.pos)
+                    .generate_code(codegen_context)?;
+                let params_t = params
+                    .iter()
+                    .map(|ty| ty.llvm_type(codegen_context))
+                    .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+                let return_t = ret.llvm_type(codegen_context)?;
+                return build_closure_call(
+                    codegen_context,
+                    closure,
+                    &params_t,
+                    return_t,
+                    &mut args_v,
+                    &self.callee,
+                );
+            }
+
+            if let Some(definition) = codegen_context.struct_types.get(&self.callee) {
+                return build_struct(codegen_context, definition.ty, &args_v, &self.callee);
+            }
+
+            log_verbose(format!("Generate function call {:?}", self.callee));
+            if callee_nf.is_null() {
+                // built-in functions can be replaced by user-defined ones of the same name
+                return match self.callee.as_str() {
+                    "print" => build_print(codegen_context, &args_v, false),
+                    "println" => build_print(codegen_context, &args_v, true),
+                    "array" => {
+                        if args_v.len() != 1 || !is_number(args_v[0]) {
+                            return Err(String::from("array expects the number of elements"));
+                        }
+                        let len = build_size(codegen_context, args_v[0], c"len");
+                        Ok(build_array_alloc(codegen_context, len))
+                    }
+                    "len" => {
+                        if args_v.len() != 1 || LLVMTypeOf(args_v[0]) != array_type(codegen_context)
+                        {
+                            return Err(String::from("len expects an array"));
+                        }
+                        let len = LLVMBuildExtractValue(
+                            codegen_context.ir_builder,
+                            args_v[0],
+                            0,
+                            c"len".as_ptr(),
+                        );
+                        Ok(LLVMBuildSIToFP(
+                            codegen_context.ir_builder,
+                            len,
+                            LLVMDoubleTypeInContext(codegen_context.context),
+                            c"lentmp".as_ptr(),
+                        ))
+                    }
+                    _ => Err(format!("Unknown function {}", self.callee)),
+                };
+            }
+            if codegen_context.current_function == Some(callee_nf) {
+                if self.is_tail_call.get() {
+                    return build_tail_call(codegen_context, callee_nf, &mut args_v, &self.callee);
+                }
+                println!(
+                    "Warning at {}: recursive call of {} is not a tail call, deep recursion can overflow the stack",
+                    self.pos, self.callee
+                );
+            }
+            build_call(codegen_context, callee_nf, &mut args_v, &self.callee)
+        }
+    }
+
+    fn mark_tail_calls(&self, name: &str) -> bool {
+        self.is_tail_call.set(self.callee == name);
+        self.is_tail_call.get()
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| arg.infer(inference))
+            .collect::<Result<Vec<Term>, String>>()?;
+
+        let variable = match inference.lookup_local(&self.callee) {
+            Some(term) => Some(term),
+            None => inference.global(&self.callee),
+        };
+        if let Some(callee) = variable {
+            let ret = match inference.resolve(&callee) {
+                Term::Function(params, ret) => {
+                    infer_call_of_type(inference, &self.callee, &params, *ret, &args, self.pos)?
+                }
+                callee => {
+                    // the arguments determine the parameter types of an unknown function
+                    let ret = inference.fresh();
+                    let function = Term::Function(args, Box::new(ret.clone()));
+                    inference.unify(&function, &callee, self.pos)?;
+                    ret
+                }
+            };
+            inference.defer(callee, self.callee_type.clone());
+            return Ok(ret);
+        }
+
+        if let Some(fields) = inference.env.structs.get(&self.callee) {
+            if fields.len() != args.len() {
+                return Err(type_error(
+                    self.pos,
+                    format!(
+                        "Struct {} has {} fields, found {} values",
+                        self.callee,
+                        fields.len(),
+                        args.len()
+                    ),
+                ));
+            }
+            for ((_, ty), arg) in fields.iter().zip(args.iter()) {
+                inference.coerce(&Term::from_type(ty), arg, self.pos)?;
+            }
+            return Ok(Term::Struct(self.callee.clone()));
+        }
+
+        if inference.signature(&self.callee).is_some() {
+            return infer_call(inference, &self.callee, &args, self.pos);
+        }
+        // built-in functions
+        match self.callee.as_str() {
+            "print" | "println" => {
+                for arg in args.iter() {
+                    if let Term::Function(..) = inference.resolve(arg) {
+                        return Err(type_error(
+                            self.pos,
+                            String::from("Cannot print a function"),
+                        ));
+                    }
+                }
+                Ok(Term::Double)
+            }
+            "array" => {
+                if args.len() != 1 {
+                    return Err(type_error(
+                        self.pos,
+                        String::from("array expects the number of elements"),
+                    ));
+                }
+                inference.expect_number(&args[0], self.pos)?;
+                Ok(Term::Array)
+            }
+            "len" => {
+                if args.len() != 1 {
+                    return Err(type_error(self.pos, String::from("len expects an array")));
+                }
+                inference.unify(&Term::Array, &args[0], self.pos)?;
+                Ok(Term::Double)
+            }
+            _ => Err(type_error(
+                self.pos,
+                format!("Unknown function {}", self.callee),
+            )),
+        }
+    }
+}
+
+// stores the arguments of a recursive tail call in the parameters and jumps back to the start
+// of the body
+fn build_tail_call(
+    codegen_context: &mut CodeGenContext,
+    function: LLVMValueRef,
+    args: &mut [LLVMValueRef],
+    name: &str,
+) -> Result<LLVMValueRef, String> {
+    log_verbose(format!("Generate tail call {:?}", name));
+    let (params_t, return_t) = function_signature(codegen_context, function);
+    convert_args(codegen_context, &params_t, args, name)?;
+    let Some(tail_recursion) = &codegen_context.tail_recursion else {
+        return Err(format!("Tail call of {} outside of its body", name));
+    };
+    unsafe {
+        // all arguments are evaluated before the first parameter is overwritten
+        for (arg, param) in args.iter().zip(tail_recursion.params.iter()) {
+            LLVMBuildStore(codegen_context.ir_builder, *arg, *param);
+        }
+        LLVMBuildBr(codegen_context.ir_builder, tail_recursion.body_bb);
+
+        // code following the jump is unreachable but still needs a block to go into
+        let unreachable_bb = LLVMAppendBasicBlockInContext(
+            codegen_context.context,
+            function,
+            c"aftertailcall".as_ptr(),
+        );
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, unreachable_bb);
+        Ok(LLVMGetUndef(return_t))
+    }
+}
+
+// anonymous function \x -> body, the locals of the enclosing function it uses are copied into
+// an environment record on the heap when the lambda is evaluated
+pub struct LambdaExprAst {
+    params: Vec<String>,
+    param_types: Vec<Option<Type>>,
+    body: Box<dyn Expr>,
+    pos: Position,
+    ty: TypeSlot,
+    // free variables of the body that are locals of the enclosing function
+    captures: RefCell<Vec<String>>,
+}
+impl LambdaExprAst {
+    pub fn new(
+        params: Vec<String>,
+        param_types: Vec<Option<Type>>,
+        body: Box<dyn Expr>,
+        pos: Position,
+    ) -> LambdaExprAst {
+        LambdaExprAst {
+            params,
+            param_types,
+            body,
+            pos,
+            ty: TypeSlot::default(),
+            captures: RefCell::default(),
+        }
+    }
+
+    fn generate_body(
+        &self,
+        codegen_context: &mut CodeGenContext,
+        code: LLVMValueRef,
+        captures: &[(String, LLVMTypeRef)],
+        env_t: LLVMTypeRef,
+    ) -> Result<(), String> {
+        unsafe {
+            let (code_params_t, return_t) = function_signature(codegen_context, code);
+            let first_arg = LLVMCountParams(code) as usize - code_params_t.len();
+            if !captures.is_empty() {
+                // the captured values are copied into allocas so that they can be assigned to
+                let env = LLVMBuildPointerCast(
+                    codegen_context.ir_builder,
+                    LLVMGetParam(code, first_arg as u32),
+                    LLVMPointerType(env_t, 0),
+                    c"env".as_ptr(),
+                );
+                for (i, (name, ty)) in captures.iter().enumerate() {
+                    let c_name = (name.clone() + "\0").into_bytes();
+                    let ptr = c_name.as_ptr() as *const i8;
+                    let field =
+                        LLVMBuildStructGEP2(codegen_context.ir_builder, env_t, env, i as u32, ptr);
+                    let value = LLVMBuildLoad2(codegen_context.ir_builder, *ty, field, ptr);
+                    let alloca = create_entry_block_alloca(codegen_context, name, *ty);
+                    LLVMBuildStore(codegen_context.ir_builder, value, alloca);
+                    codegen_context.named_values.insert(name.clone(), alloca);
+                }
+            }
+            for (i, (name, param_t)) in self.params.iter().zip(&code_params_t[1..]).enumerate() {
+                let param = LLVMGetParam(code, (first_arg + 1 + i) as u32);
+                let param = build_param_value(codegen_context, param, *param_t);
+                let alloca = create_entry_block_alloca(codegen_context, name, *param_t);
+                LLVMBuildStore(codegen_context.ir_builder, param, alloca);
+                codegen_context.named_values.insert(name.clone(), alloca);
+            }
+
+            let return_value = self.body.generate_code(codegen_context)?;
+            let Some(return_value) = build_conversion(codegen_context, return_value, return_t)
+            else {
+                return Err(format!(
+                    "Lambda must return a {}, found {}",
+                    type_name(return_t),
+                    type_name(LLVMTypeOf(return_value))
+                ));
+            };
+            build_return(codegen_context, return_value);
+            Ok(())
+        }
+    }
+}
+impl Expr for LambdaExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(String::from("Generate lambda expr"));
+        unsafe {
+            let Some(Type::Function(params, ret)) = self.ty.borrow().clone() else {
+                return Err(String::from("Lambda has no inferred function type"));
+            };
+            let params_t = params
+                .iter()
+                .map(|ty| ty.llvm_type(codegen_context))
+                .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+            let return_t = ret.llvm_type(codegen_context)?;
+
+            // the free variables are looked up before the code of the lambda is generated
+            let mut captures = vec![];
+            let mut captured_values = vec![];
+            for name in self.captures.borrow().iter() {
+                let Some(alloca) = codegen_context.named_values.get(name) else {
+                    return Err(format!("Unknown variable name {}", name));
+                };
+                let ty = LLVMGetAllocatedType(*alloca);
+                let c_name = (name.clone() + "\0").into_bytes();
+                let value = LLVMBuildLoad2(
+                    codegen_context.ir_builder,
+                    ty,
+                    *alloca,
+                    c_name.as_ptr() as *const i8,
+                );
+                captures.push((name.clone(), ty));
+                captured_values.push(value);
+            }
+            let mut captures_t: Vec<LLVMTypeRef> = captures.iter().map(|(_, ty)| *ty).collect();
+            let env_t = LLVMStructTypeInContext(
+                codegen_context.context,
+                captures_t.as_mut_ptr(),
+                captures_t.len() as u32,
+                false as i32,
+            );
+
+            let code = add_closure_code(codegen_context, "lambda", &params_t, return_t);
+            let enclosing = enter_function(codegen_context, code);
+            let result = self.generate_body(codegen_context, code, &captures, env_t);
+            leave_function(codegen_context, enclosing);
+            if let Err(e) = result {
+                LLVMDeleteFunction(code);
+                return Err(e);
+            }
+            LLVMVerifyFunction(
+                code,
+                llvm_sys::analysis::LLVMVerifierFailureAction::LLVMPrintMessageAction,
+            );
+
+            // lambdas without captures do not need an environment
+            let env = if captures.is_empty() {
+                LLVMConstNull(env_type(codegen_context))
+            } else {
+                let env = build_alloc(codegen_context, env_t);
+                let record = LLVMBuildPointerCast(
+                    codegen_context.ir_builder,
+                    env,
+                    LLVMPointerType(env_t, 0),
+                    c"record".as_ptr(),
+                );
+                for (i, value) in captured_values.into_iter().enumerate() {
+                    let field = LLVMBuildStructGEP2(
+                        codegen_context.ir_builder,
+                        env_t,
+                        record,
+                        i as u32,
+                        c"capture".as_ptr(),
+                    );
+                    LLVMBuildStore(codegen_context.ir_builder, value, field);
+                }
+                env
+            };
+            Ok(build_closure(codegen_context, code, env))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let params: Vec<Term> = self
+            .param_types
+            .iter()
+            .map(|ty| match ty {
+                Some(ty) => Term::from_type(ty),
+                None => inference.fresh(),
+            })
+            .collect();
+        let ret = inference.fresh();
+        inference.enter_lambda();
+        for (name, param) in self.params.iter().zip(params.iter()) {
+            inference.locals.insert(name.clone(), param.clone());
+        }
+        let body = self.body.infer(inference);
+        *self.captures.borrow_mut() = inference.leave_lambda();
+        inference.coerce(&ret, &body?, self.pos)?;
+        let function = Term::Function(params, Box::new(ret));
+        inference.defer(function.clone(), self.ty.clone());
+        Ok(function)
+    }
+}
+
+// assert(cond) and assert(cond, code), exits with the code if the condition is false
+pub struct AssertExprAst {
+    cond: Box<dyn Expr>,
+    code: Option<Box<dyn Expr>>,
+    pos: Position,
+}
+impl AssertExprAst {
+    pub fn new(cond: Box<dyn Expr>, code: Option<Box<dyn Expr>>, pos: Position) -> AssertExprAst {
+        AssertExprAst { cond, code, pos }
+    }
+}
+impl Expr for AssertExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            if codegen_context.options.strip_asserts {
+                log_verbose(format!("Strip assert at line {}", self.pos.line));
+                return Ok(LLVMConstReal(dt, 0.0));
+            }
+
+            let cond = self.cond.generate_code(codegen_context)?;
+            let cond = build_condition(codegen_context, cond, c"assertcond")?;
+            let i32_t = LLVMInt32TypeInContext(codegen_context.context);
+            // the exit code is only evaluated if the assert fails
+            build_assert(
+                codegen_context,
+                cond,
+                self.pos.line,
+                |codegen_context| match &self.code {
+                    Some(code) => {
+                        let code = code.generate_code(codegen_context)?;
+                        build_conversion(codegen_context, code, i32_t).ok_or_else(|| {
+                            format!(
+                                "Exit code of assert must be a number, found {}",
+                                type_name(LLVMTypeOf(code))
+                            )
+                        })
+                    }
+                    None => Ok(LLVMConstInt(i32_t, 1, false as i32)),
+                },
+            )?;
+            Ok(LLVMConstReal(dt, 0.0))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let cond = self.cond.infer(inference)?;
+        inference.expect_number(&cond, self.pos)?;
+        if let Some(code) = &self.code {
+            let code = code.infer(inference)?;
+            inference.expect_number(&code, self.pos)?;
+        }
+        Ok(Term::Double)
+    }
+}
+
+pub struct IfExprAst {
+    cond: Box<dyn Expr>,
+    then: Box<dyn Expr>,
+    otherwise: Box<dyn Expr>,
+    pos: Position,
+}
+impl IfExprAst {
+    pub fn new(
+        cond: Box<dyn Expr>,
+        then: Box<dyn Expr>,
+        otherwise: Box<dyn Expr>,
+        pos: Position,
+    ) -> IfExprAst {
+        IfExprAst {
+            cond,
+            then,
+            otherwise,
+            pos,
+        }
+    }
+}
+impl Expr for IfExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let cond_value = self.cond.generate_code(codegen_context)?;
+
+            log_verbose(String::from("Generate if expr"));
+            let cond_value = build_condition(codegen_context, cond_value, c"ifcond")?;
+
+            let function = codegen_context.current_function.unwrap();
+            let then_bb =
+                LLVMAppendBasicBlockInContext(codegen_context.context, function, c"then".as_ptr());
+            let else_bb = LLVMCreateBasicBlockInContext(codegen_context.context, c"else".as_ptr());
+            let merge_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"ifcont".as_ptr());
+            LLVMBuildCondBr(codegen_context.ir_builder, cond_value, then_bb, else_bb);
+
+            // then branch
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, then_bb);
+            let then_value = self.then.generate_code(codegen_context)?;
+            let then_br = LLVMBuildBr(codegen_context.ir_builder, merge_bb);
+            // codegen of the branch can change the current block, update it for the phi
+            let then_bb = LLVMGetInsertBlock(codegen_context.ir_builder);
+
+            // else branch
+            LLVMAppendExistingBasicBlock(function, else_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, else_bb);
+            let else_value = self.otherwise.generate_code(codegen_context)?;
+            let else_br = LLVMBuildBr(codegen_context.ir_builder, merge_bb);
+            let else_bb = LLVMGetInsertBlock(codegen_context.ir_builder);
+
+            // numbers of different types are converted at the end of their branch
+            let mut value_t = LLVMTypeOf(then_value);
+            let (mut then_value, mut else_value) = (then_value, else_value);
+            if LLVMTypeOf(else_value) != value_t && is_number(then_value) && is_number(else_value) {
+                value_t = common_number_type(codegen_context, then_value, else_value);
+                LLVMPositionBuilderBefore(codegen_context.ir_builder, then_br);
+                then_value = build_conversion(codegen_context, then_value, value_t).unwrap();
+                LLVMPositionBuilderBefore(codegen_context.ir_builder, else_br);
+                else_value = build_conversion(codegen_context, else_value, value_t).unwrap();
+            }
+
+            // merge both branches
+            LLVMAppendExistingBasicBlock(function, merge_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, merge_bb);
+            if LLVMTypeOf(else_value) != value_t {
+                return Err(format!(
+                    "Branches of if have different types {} and {}",
+                    type_name(value_t),
+                    type_name(LLVMTypeOf(else_value))
+                ));
+            }
+            let phi = LLVMBuildPhi(codegen_context.ir_builder, value_t, c"iftmp".as_ptr());
+            let mut incoming_values = [then_value, else_value];
+            let mut incoming_blocks = [then_bb, else_bb];
+            LLVMAddIncoming(
+                phi,
+                incoming_values.as_mut_ptr(),
+                incoming_blocks.as_mut_ptr(),
+                2,
+            );
+            Ok(phi)
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let cond = self.cond.infer(inference)?;
+        inference.expect_number(&cond, self.pos)?;
+        let then = self.then.infer(inference)?;
+        let otherwise = self.otherwise.infer(inference)?;
+        inference.join(&then, &otherwise, self.pos)
+    }
+
+    fn mark_tail_calls(&self, name: &str) -> bool {
+        // both branches are marked
+        self.then.mark_tail_calls(name) | self.otherwise.mark_tail_calls(name)
+    }
+}
+
+// pattern of an arm of a match expression
+pub enum Pattern {
+    // matches any of the numbers
+    Constants(Vec<f64>),
+    // matches any value and binds it to the name
+    Binding(String),
+    // _ matches any value
+    Wildcard,
+}
+
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Box<dyn Expr>>,
+    pub body: Box<dyn Expr>,
+}
+impl MatchArm {
+    // arm that matches every value
+    pub fn is_catch_all(&self) -> bool {
+        self.guard.is_none() && !matches!(self.pattern, Pattern::Constants(_))
+    }
+
+    fn is_integral(&self) -> bool {
+        match &self.pattern {
+            Pattern::Constants(constants) => constants
+                .iter()
+                .all(|c| c.fract() == 0.0 && c.abs() <= i32::MAX as f64),
+            _ => true,
+        }
+    }
+}
+
+// match value { patterns => expr, ... }, the first arm whose pattern matches the value and
+// whose guard is true is evaluated, a value no arm matches evaluates to 0
+pub struct MatchExprAst {
+    value: Box<dyn Expr>,
+    arms: Vec<MatchArm>,
+    pos: Position,
+}
+impl MatchExprAst {
+    pub fn new(value: Box<dyn Expr>, arms: Vec<MatchArm>, pos: Position) -> MatchExprAst {
+        MatchExprAst { value, arms, pos }
+    }
+
+    // i1 that is true if the value equals one of the constants, ints are compared as ints
+    // unless a constant has a fractional part or is out of the range of ints
+    fn build_constants_test(
+        &self,
+        codegen_context: &mut CodeGenContext,
+        value: LLVMValueRef,
+        constants: &[f64],
+    ) -> LLVMValueRef {
+        unsafe {
+            let builder = codegen_context.ir_builder;
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let is_int_test = is_int(value)
+                && constants
+                    .iter()
+                    .all(|c| c.fract() == 0.0 && c.abs() <= i32::MAX as f64);
+            let value = if is_int_test {
+                value
+            } else {
+                build_conversion(codegen_context, value, dt).unwrap()
+            };
+            let mut result = None;
+            for constant in constants.iter() {
+                let cmp = if is_int_test {
+                    LLVMBuildICmp(
+                        builder,
+                        llvm::LLVMIntPredicate::LLVMIntEQ,
+                        value,
+                        LLVMConstInt(
+                            int_type(codegen_context),
+                            *constant as i64 as u64,
+                            true as i32,
+                        ),
+                        c"matchcmp".as_ptr(),
+                    )
+                } else {
+                    LLVMBuildFCmp(
+                        builder,
+                        llvm::LLVMRealPredicate::LLVMRealOEQ,
+                        value,
+                        LLVMConstReal(dt, *constant),
+                        c"matchcmp".as_ptr(),
+                    )
+                };
+                result = Some(match result {
+                    Some(result) => LLVMBuildOr(builder, result, cmp, c"matchtest".as_ptr()),
+                    None => cmp,
+                });
+            }
+            // patterns have at least one constant
+            result.unwrap()
+        }
+    }
+
+    // restores the variable shadowed by the binding of an arm, also if the arm fails to generate
+    fn restore_binding(
+        codegen_context: &mut CodeGenContext,
+        shadowed: Option<(&String, Option<LLVMValueRef>)>,
+    ) {
+        if let Some((name, shadowed_value)) = shadowed {
+            match shadowed_value {
+                Some(value) => codegen_context.named_values.insert(name.clone(), value),
+                None => codegen_context.named_values.remove(name),
+            };
+        }
+    }
+
+    // the double converted to an int to switch on, branches to other_bb instead if it is not
+    // an integral value in the range of ints
+    fn build_integral_value(
+        &self,
+        codegen_context: &mut CodeGenContext,
+        value: LLVMValueRef,
+        other_bb: LLVMBasicBlockRef,
+    ) -> LLVMValueRef {
+        unsafe {
+            let builder = codegen_context.ir_builder;
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let it = int_type(codegen_context);
+            // out of range values are not converted, as the conversion would be undefined
+            let above_min = LLVMBuildFCmp(
+                builder,
+                llvm::LLVMRealPredicate::LLVMRealOGE,
+                value,
+                LLVMConstReal(dt, i32::MIN as f64),
+                c"abovemin".as_ptr(),
+            );
+            let below_max = LLVMBuildFCmp(
+                builder,
+                llvm::LLVMRealPredicate::LLVMRealOLE,
+                value,
+                LLVMConstReal(dt, i32::MAX as f64),
+                c"belowmax".as_ptr(),
+            );
+            let in_range = LLVMBuildAnd(builder, above_min, below_max, c"inrange".as_ptr());
+            let in_range_value = LLVMBuildSelect(
+                builder,
+                in_range,
+                value,
+                LLVMConstReal(dt, 0.0),
+                c"inrangevalue".as_ptr(),
+            );
+            let int_value = LLVMBuildFPToSI(builder, in_range_value, it, c"intvalue".as_ptr());
+            let round_trip = LLVMBuildSIToFP(builder, int_value, dt, c"roundtrip".as_ptr());
+            let is_exact = LLVMBuildFCmp(
+                builder,
+                llvm::LLVMRealPredicate::LLVMRealOEQ,
+                round_trip,
+                value,
+                c"isexact".as_ptr(),
+            );
+            let is_integral = LLVMBuildAnd(builder, in_range, is_exact, c"isintegral".as_ptr());
+
+            let function = codegen_context.current_function.unwrap();
+            let switch_bb = LLVMAppendBasicBlockInContext(
+                codegen_context.context,
+                function,
+                c"switch".as_ptr(),
+            );
+            LLVMBuildCondBr(builder, is_integral, switch_bb, other_bb);
+            LLVMPositionBuilderAtEnd(builder, switch_bb);
+            int_value
+        }
+    }
+}
+impl Expr for MatchExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let value = self.value.generate_code(codegen_context)?;
+            if !is_number(value) {
+                return Err(format!("Cannot match a {}", type_name(LLVMTypeOf(value))));
+            }
+
+            log_verbose(String::from("Generate match expr"));
+            let builder = codegen_context.ir_builder;
+            let function = codegen_context.current_function.unwrap();
+            let merge_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"matchcont".as_ptr());
+            let nomatch_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"nomatch".as_ptr());
+            let arm_bbs: Vec<LLVMBasicBlockRef> = self
+                .arms
+                .iter()
+                .map(|_| LLVMCreateBasicBlockInContext(codegen_context.context, c"arm".as_ptr()))
+                .collect();
+
+            // bound values are stored like variables, so that they can be assigned
+            let bindings: Vec<Option<(&String, LLVMValueRef)>> = self
+                .arms
+                .iter()
+                .map(|arm| match &arm.pattern {
+                    Pattern::Binding(name) => {
+                        let alloca =
+                            create_entry_block_alloca(codegen_context, name, LLVMTypeOf(value));
+                        LLVMBuildStore(builder, value, alloca);
+                        Some((name, alloca))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            // numbers matched against integer constants without guards jump to the arm directly,
+            // doubles only if they are integral
+            let is_switch = self
+                .arms
+                .iter()
+                .all(|arm| arm.guard.is_none() && arm.is_integral());
+            if is_switch {
+                let catch_all = self.arms.iter().position(|arm| arm.is_catch_all());
+                let default_bb = match catch_all {
+                    Some(i) => arm_bbs[i],
+                    None => nomatch_bb,
+                };
+                let switch_value = if is_double(value) {
+                    self.build_integral_value(codegen_context, value, default_bb)
+                } else {
+                    build_conversion(codegen_context, value, int_type(codegen_context)).unwrap()
+                };
+                let switch =
+                    LLVMBuildSwitch(builder, switch_value, default_bb, self.arms.len() as u32);
+                let mut cases = vec![];
+                // arms after the first catch-all arm are never matched
+                let matchable_arms = catch_all.unwrap_or(self.arms.len());
+                for (arm, arm_bb) in self.arms.iter().zip(arm_bbs.iter()).take(matchable_arms) {
+                    let Pattern::Constants(constants) = &arm.pattern else {
+                        continue;
+                    };
+                    // a value matched by an earlier arm stays with it
+                    for constant in constants.iter().map(|c| *c as i64) {
+                        if !cases.contains(&constant) {
+                            cases.push(constant);
+                            let case = LLVMConstInt(int_type(codegen_context), constant as u64, 1);
+                            LLVMAddCase(switch, case, *arm_bb);
+                        }
+                    }
+                }
+            }
+
+            let mut results = vec![];
+            for (i, arm) in self.arms.iter().enumerate() {
+                let shadowed = bindings[i].map(|(name, alloca)| {
+                    (
+                        name,
+                        codegen_context.named_values.insert(name.clone(), alloca),
+                    )
+                });
+
+                // otherwise the arms are tested one after the other, the test of the next arm
+                // follows the body of this arm
+                let mut next_bb = None;
+                if !is_switch {
+                    let test_next_bb = if i + 1 < self.arms.len() {
+                        LLVMCreateBasicBlockInContext(codegen_context.context, c"next".as_ptr())
+                    } else {
+                        nomatch_bb
+                    };
+                    if let Pattern::Constants(constants) = &arm.pattern {
+                        let test = self.build_constants_test(codegen_context, value, constants);
+                        let matched_bb = LLVMAppendBasicBlockInContext(
+                            codegen_context.context,
+                            function,
+                            c"matched".as_ptr(),
+                        );
+                        LLVMBuildCondBr(builder, test, matched_bb, test_next_bb);
+                        LLVMPositionBuilderAtEnd(builder, matched_bb);
+                    }
+                    match &arm.guard {
+                        Some(guard) => {
+                            let guard_value = guard
+                                .generate_code(codegen_context)
+                                .and_then(|guard_value| {
+                                    build_condition(codegen_context, guard_value, c"guard")
+                                })
+                                .inspect_err(|_| {
+                                    Self::restore_binding(codegen_context, shadowed)
+                                })?;
+                            LLVMBuildCondBr(builder, guard_value, arm_bbs[i], test_next_bb);
+                        }
+                        None => {
+                            LLVMBuildBr(builder, arm_bbs[i]);
+                        }
+                    }
+                    if test_next_bb != nomatch_bb {
+                        next_bb = Some(test_next_bb);
+                    }
+                }
+
+                LLVMAppendExistingBasicBlock(function, arm_bbs[i]);
+                LLVMPositionBuilderAtEnd(builder, arm_bbs[i]);
+                let arm_value = arm
+                    .body
+                    .generate_code(codegen_context)
+                    .inspect_err(|_| Self::restore_binding(codegen_context, shadowed))?;
+                results.push((arm_value, LLVMBuildBr(builder, merge_bb)));
+
+                if let Some(next_bb) = next_bb {
+                    LLVMAppendExistingBasicBlock(function, next_bb);
+                    LLVMPositionBuilderAtEnd(builder, next_bb);
+                }
+                Self::restore_binding(codegen_context, shadowed);
+            }
+
+            // numbers of different types are converted at the end of their arm
+            let Some(&(first_value, _)) = results.first() else {
+                return Err(String::from("Match without arms"));
+            };
+            let mut value_t = LLVMTypeOf(first_value);
+            if let Some(&(other_value, _)) = results
+                .iter()
+                .find(|(arm_value, _)| LLVMTypeOf(*arm_value) != value_t)
+            {
+                if !results.iter().all(|(arm_value, _)| is_number(*arm_value)) {
+                    return Err(format!(
+                        "Arms of match have different types {} and {}",
+                        type_name(value_t),
+                        type_name(LLVMTypeOf(other_value))
+                    ));
+                }
+                value_t = if results.iter().all(|(arm_value, _)| is_integral(*arm_value)) {
+                    int_type(codegen_context)
+                } else {
+                    LLVMDoubleTypeInContext(codegen_context.context)
+                };
+                for (arm_value, arm_br) in results.iter_mut() {
+                    LLVMPositionBuilderBefore(builder, *arm_br);
+                    *arm_value = build_conversion(codegen_context, *arm_value, value_t).unwrap();
+                }
+            }
+
+            // values no arm matches evaluate to 0
+            LLVMAppendExistingBasicBlock(function, nomatch_bb);
+            LLVMPositionBuilderAtEnd(builder, nomatch_bb);
+            LLVMBuildBr(builder, merge_bb);
+
+            // merge all arms
+            LLVMAppendExistingBasicBlock(function, merge_bb);
+            LLVMPositionBuilderAtEnd(builder, merge_bb);
+            let phi = LLVMBuildPhi(builder, value_t, c"matchtmp".as_ptr());
+            let mut incoming_values: Vec<LLVMValueRef> = results
+                .iter()
+                .map(|(arm_value, _)| *arm_value)
+                .chain(std::iter::once(LLVMConstNull(value_t)))
+                .collect();
+            let mut incoming_blocks: Vec<LLVMBasicBlockRef> = results
+                .iter()
+                .map(|(_, arm_br)| LLVMGetInstructionParent(*arm_br))
+                .chain(std::iter::once(nomatch_bb))
+                .collect();
+            LLVMAddIncoming(
+                phi,
+                incoming_values.as_mut_ptr(),
+                incoming_blocks.as_mut_ptr(),
+                incoming_values.len() as u32,
+            );
+            Ok(phi)
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let value = self.value.infer(inference)?;
+        inference.expect_number(&value, self.pos)?;
+        let mut result: Option<Term> = None;
+        for arm in self.arms.iter() {
+            let shadowed = match &arm.pattern {
+                Pattern::Binding(name) => {
+                    Some((name, inference.locals.insert(name.clone(), value.clone())))
+                }
+                _ => None,
+            };
+            if let Some(guard) = &arm.guard {
+                let guard = guard.infer(inference)?;
+                inference.expect_number(&guard, self.pos)?;
+            }
+            let body = arm.body.infer(inference)?;
+            result = Some(match result {
+                Some(result) => inference.join(&result, &body, self.pos)?,
+                None => body,
+            });
+            if let Some((name, term)) = shadowed {
+                match term {
+                    Some(term) => inference.locals.insert(name.clone(), term),
+                    None => inference.locals.remove(name),
+                };
+            }
+        }
+        Ok(result.unwrap_or(Term::Double))
+    }
+
+    fn mark_tail_calls(&self, name: &str) -> bool {
+        // every arm is marked
+        self.arms
+            .iter()
+            .fold(false, |marked, arm| arm.body.mark_tail_calls(name) | marked)
+    }
+}
+
+pub struct ForExprAst {
+    var_name: String,
+    start: Box<dyn Expr>,
+    end: Box<dyn Expr>,
+    step: Option<Box<dyn Expr>>,
+    body: Box<dyn Expr>,
+    pos: Position,
+    var_type: TypeSlot,
+}
+impl ForExprAst {
+    pub fn new(
+        var_name: String,
+        start: Box<dyn Expr>,
+        end: Box<dyn Expr>,
+        step: Option<Box<dyn Expr>>,
+        body: Box<dyn Expr>,
+        pos: Position,
+    ) -> ForExprAst {
+        ForExprAst {
+            var_name,
+            start,
+            end,
+            step,
+            body,
+            pos,
+            var_type: TypeSlot::default(),
+        }
+    }
+
+    // the body, step and end condition of the loop, leaves the builder behind the loop
+    fn generate_loop(
+        &self,
+        codegen_context: &mut CodeGenContext,
+        alloca: LLVMValueRef,
+        var_t: LLVMTypeRef,
+        loop_bb: LLVMBasicBlockRef,
+    ) -> Result<(), String> {
+        unsafe {
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let function = codegen_context.current_function.unwrap();
+
+            // continue jumps to the step block, break behind the loop
+            let step_bb = LLVMCreateBasicBlockInContext(codegen_context.context, c"step".as_ptr());
+            let after_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"afterloop".as_ptr());
+            codegen_context.loop_targets.push(LoopTargets {
+                continue_bb: step_bb,
+                break_bb: after_bb,
+            });
+            let body_result = self.body.generate_code(codegen_context);
+            codegen_context.loop_targets.pop();
+            body_result?;
+
+            LLVMBuildBr(codegen_context.ir_builder, step_bb);
+            LLVMAppendExistingBasicBlock(function, step_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, step_bb);
+
+            let step_value = match &self.step {
+                Some(step) => step.generate_code(codegen_context)?,
+                None => LLVMConstReal(dt, 1.0),
+            };
+
+            let end_value = self.end.generate_code(codegen_context)?;
+
+            // the body and end condition may have assigned the loop variable, reload it
+            let name = (self.var_name.clone() + "\0").into_bytes();
+            let cur_value = LLVMBuildLoad2(
+                codegen_context.ir_builder,
+                var_t,
+                alloca,
+                name.as_ptr() as *const i8,
+            );
+            if !is_number(step_value) {
+                return Err(format!(
+                    "For loop step must be a number, found {}",
+                    type_name(LLVMTypeOf(step_value))
+                ));
+            }
+            // the step is added like by the + operator and converted back to the loop variable
+            let mut value_t = common_number_type(codegen_context, cur_value, step_value);
+            if value_t == bool_type(codegen_context) {
+                value_t = int_type(codegen_context);
+            }
+            let cur_value = build_conversion(codegen_context, cur_value, value_t).unwrap();
+            let step_value = build_conversion(codegen_context, step_value, value_t).unwrap();
+            let next_value = if value_t == dt {
+                LLVMBuildFAdd(
+                    codegen_context.ir_builder,
+                    cur_value,
+                    step_value,
+                    c"nextvar".as_ptr(),
+                )
+            } else {
+                LLVMBuildAdd(
+                    codegen_context.ir_builder,
+                    cur_value,
+                    step_value,
+                    c"nextvar".as_ptr(),
+                )
+            };
+            let next_value = build_conversion(codegen_context, next_value, var_t).unwrap();
+            LLVMBuildStore(codegen_context.ir_builder, next_value, alloca);
 
-pub struct NumberExprAst {
-    val: f64,
-}
-impl NumberExprAst {
-    pub fn new(val: f64) -> NumberExprAst {
-        NumberExprAst { val }
+            let end_cond = build_condition(codegen_context, end_value, c"loopcond")?;
+
+            LLVMBuildCondBr(codegen_context.ir_builder, end_cond, loop_bb, after_bb);
+            LLVMAppendExistingBasicBlock(function, after_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, after_bb);
+            Ok(())
+        }
     }
 }
-impl Expr for NumberExprAst {
-    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
-    //     treeprinter.add_print_item(self.val.to_string(), depth, indent_lvl);
-    // }
-
+impl Expr for ForExprAst {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
-        log_verbose(format!("Generate number expr {:?}", self.val));
         unsafe {
-            let ft = LLVMDoubleTypeInContext(codegen_context.context);
-            Ok(LLVMConstReal(ft, self.val))
+            log_verbose(format!("Generate for expr {:?}", self.var_name));
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let var_t = match &*self.var_type.borrow() {
+                Some(ty) => ty.llvm_type(codegen_context)?,
+                None => dt,
+            };
+            let alloca = create_entry_block_alloca(codegen_context, &self.var_name, var_t);
+
+            // the start value is evaluated before the loop variable is in scope
+            let start_value = self.start.generate_code(codegen_context)?;
+            let Some(start_value) = build_conversion(codegen_context, start_value, var_t) else {
+                return Err(format!(
+                    "For loop variable must be a number, found {}",
+                    type_name(LLVMTypeOf(start_value))
+                ));
+            };
+            LLVMBuildStore(codegen_context.ir_builder, start_value, alloca);
+
+            let function = codegen_context.current_function.unwrap();
+            let loop_bb =
+                LLVMAppendBasicBlockInContext(codegen_context.context, function, c"loop".as_ptr());
+            LLVMBuildBr(codegen_context.ir_builder, loop_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, loop_bb);
+
+            // shadow an existing variable of the same name for the duration of the loop
+            let shadowed_value = codegen_context
+                .named_values
+                .insert(self.var_name.clone(), alloca);
+
+            // the shadowed variable is restored before an error of the loop is returned
+            let result = self.generate_loop(codegen_context, alloca, var_t, loop_bb);
+
+            match shadowed_value {
+                Some(value) => codegen_context
+                    .named_values
+                    .insert(self.var_name.clone(), value),
+                None => codegen_context.named_values.remove(&self.var_name),
+            };
+            result?;
+
+            // a for loop always evaluates to 0.0
+            Ok(LLVMConstReal(dt, 0.0))
+        }
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let start = self.start.infer(inference)?;
+        inference.expect_number(&start, self.pos)?;
+        let shadowed = inference
+            .locals
+            .insert(self.var_name.clone(), start.clone());
+
+        let end = self.end.infer(inference)?;
+        inference.expect_number(&end, self.pos)?;
+        if let Some(step) = &self.step {
+            let step = step.infer(inference)?;
+            inference.expect_number(&step, self.pos)?;
+            // a fractional step makes the loop variable a double
+            inference.join(&start, &step, self.pos)?;
         }
+        self.body.infer(inference)?;
+
+        match shadowed {
+            Some(term) => inference.locals.insert(self.var_name.clone(), term),
+            None => inference.locals.remove(&self.var_name),
+        };
+        inference.defer(start, self.var_type.clone());
+        Ok(Term::Double)
     }
 }
 
-pub struct BinaryExprAst {
-    op: String,
-    lhs: Box<dyn Expr>,
-    rhs: Box<dyn Expr>,
+pub struct WhileExprAst {
+    cond: Box<dyn Expr>,
+    body: Box<dyn Expr>,
+    pos: Position,
 }
-impl BinaryExprAst {
-    pub fn new(op: String, lhs: Box<dyn Expr>, rhs: Box<dyn Expr>) -> BinaryExprAst {
-        BinaryExprAst { op, lhs, rhs }
+impl WhileExprAst {
+    pub fn new(cond: Box<dyn Expr>, body: Box<dyn Expr>, pos: Position) -> WhileExprAst {
+        WhileExprAst { cond, body, pos }
     }
 }
-impl Expr for BinaryExprAst {
-    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
-    //     treeprinter.add_print_item(self.op.clone(), depth, indent_lvl);
-    //     self.lhs.print(treeprinter, indent_lvl - 1, depth + 1);
-    //     self.rhs.print(treeprinter, indent_lvl + 1, depth + 1);
-    // }
-
+impl Expr for WhileExprAst {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
         unsafe {
-            let lhs_value = self.lhs.generate_code(codegen_context)?;
-            let rhs_value = self.rhs.generate_code(codegen_context)?;
+            log_verbose(String::from("Generate while expr"));
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let function = codegen_context.current_function.unwrap();
+            let cond_bb = LLVMAppendBasicBlockInContext(
+                codegen_context.context,
+                function,
+                c"whilecond".as_ptr(),
+            );
+            LLVMBuildBr(codegen_context.ir_builder, cond_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, cond_bb);
 
-            log_verbose(format!("Generate binary expr {:?}", self.op));
-            let name = c"op".as_ptr() as *const _;
+            let cond_value = self.cond.generate_code(codegen_context)?;
+            let cond_value = build_condition(codegen_context, cond_value, c"whilecond")?;
+            let body_bb = LLVMAppendBasicBlockInContext(
+                codegen_context.context,
+                function,
+                c"whilebody".as_ptr(),
+            );
+            let after_bb =
+                LLVMCreateBasicBlockInContext(codegen_context.context, c"afterwhile".as_ptr());
+            LLVMBuildCondBr(codegen_context.ir_builder, cond_value, body_bb, after_bb);
 
-            match self.op.as_str() {
-                "+" => Ok(LLVMBuildFAdd(
-                    codegen_context.ir_builder,
-                    lhs_value,
-                    rhs_value,
-                    name,
-                )),
-                "-" => Ok(LLVMBuildFSub(
-                    codegen_context.ir_builder,
-                    lhs_value,
-                    rhs_value,
-                    name,
-                )),
-                "*" => Ok(LLVMBuildFMul(
-                    codegen_context.ir_builder,
-                    lhs_value,
-                    rhs_value,
-                    name,
-                )),
-                //"/" => Err("Division not implemented")?,
-                _ => Err(format!("Invalid binary operator {}", self.op)),
-            }
+            // continue re-evaluates the condition, break jumps behind the loop
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, body_bb);
+            codegen_context.loop_targets.push(LoopTargets {
+                continue_bb: cond_bb,
+                break_bb: after_bb,
+            });
+            // the targets are popped before an error of the body is returned
+            let body_result = self.body.generate_code(codegen_context);
+            codegen_context.loop_targets.pop();
+            body_result?;
+            LLVMBuildBr(codegen_context.ir_builder, cond_bb);
+
+            LLVMAppendExistingBasicBlock(function, after_bb);
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, after_bb);
+
+            // a while loop always evaluates to 0.0
+            Ok(LLVMConstReal(dt, 0.0))
         }
     }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let cond = self.cond.infer(inference)?;
+        inference.expect_number(&cond, self.pos)?;
+        self.body.infer(inference)?;
+        Ok(Term::Double)
+    }
 }
 
-pub struct VariableExprAst {
-    name: String,
+// break and continue of the innermost loop
+pub struct LoopControlExprAst {
+    is_break: bool,
 }
-impl VariableExprAst {
-    pub fn new(name: String) -> VariableExprAst {
-        VariableExprAst { name }
+impl LoopControlExprAst {
+    pub fn new(is_break: bool) -> LoopControlExprAst {
+        LoopControlExprAst { is_break }
     }
 }
-impl Expr for VariableExprAst {
-    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
-    //     treeprinter.add_print_item(self.name.clone(), depth, indent_lvl);
-    // }
-
+impl Expr for LoopControlExprAst {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
-        log_verbose(format!("Generate variable expr {:?}", self.name));
+        let keyword = if self.is_break { "break" } else { "continue" };
+        log_verbose(format!("Generate {}", keyword));
+        let targets = match codegen_context.loop_targets.last() {
+            Some(targets) => targets,
+            None => return Err(format!("'{}' outside of a loop", keyword)),
+        };
+        let target_bb = if self.is_break {
+            targets.break_bb
+        } else {
+            targets.continue_bb
+        };
         unsafe {
-            if codegen_context.named_values.contains_key(&self.name) {
-                let index = codegen_context.named_values.get(&self.name).unwrap();
-                Ok(LLVMGetParam(
-                    codegen_context.current_function.unwrap(),
-                    *index,
-                ))
-            } else {
-                Err(format!("Unknown variable name {}", self.name))
-            }
+            LLVMBuildBr(codegen_context.ir_builder, target_bb);
+
+            // code following the jump is unreachable but still needs a block to go into
+            let function = codegen_context.current_function.unwrap();
+            let unreachable_bb = LLVMAppendBasicBlockInContext(
+                codegen_context.context,
+                function,
+                c"afterjump".as_ptr(),
+            );
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, unreachable_bb);
+            Ok(LLVMConstReal(
+                LLVMDoubleTypeInContext(codegen_context.context),
+                0.0,
+            ))
         }
     }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        // the value is never used, so it fits anywhere
+        Ok(inference.fresh())
+    }
 }
 
-pub struct FunctionCallExprAst {
-    pub callee: String,
-    pub args: Vec<Box<dyn Expr>>,
+pub struct BlockExprAst {
+    exprs: Vec<Box<dyn Expr>>,
 }
-impl FunctionCallExprAst {
-    pub fn new(callee: String, args: Vec<Box<dyn Expr>>) -> FunctionCallExprAst {
-        FunctionCallExprAst { callee, args }
+impl BlockExprAst {
+    pub fn new(exprs: Vec<Box<dyn Expr>>) -> BlockExprAst {
+        BlockExprAst { exprs }
+    }
+}
+impl Expr for BlockExprAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        log_verbose(format!(
+            "Generate block of {} expressions",
+            self.exprs.len()
+        ));
+        // a block evaluates to its last expression, an empty block to 0.0
+        let mut value =
+            unsafe { LLVMConstReal(LLVMDoubleTypeInContext(codegen_context.context), 0.0) };
+        for expr in self.exprs.iter() {
+            value = expr.generate_code(codegen_context)?;
+        }
+        Ok(value)
+    }
+
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let mut term = Term::Double;
+        for expr in self.exprs.iter() {
+            term = expr.infer(inference)?;
+        }
+        Ok(term)
+    }
+
+    fn mark_tail_calls(&self, name: &str) -> bool {
+        self.exprs
+            .last()
+            .is_some_and(|expr| expr.mark_tail_calls(name))
     }
 }
-impl Expr for FunctionCallExprAst {
-    // fn print(&self, treeprinter: &mut TreePrinter, indent_lvl: i32, depth: i32) {
-    //     // TODO args
-    //     treeprinter.add_print_item(self.callee.clone(), depth, indent_lvl);
-    // }
 
+pub struct VarExprAst {
+    vars: Vec<(String, Option<Box<dyn Expr>>)>,
+    body: Box<dyn Expr>,
+    var_types: Vec<TypeSlot>,
+}
+impl VarExprAst {
+    pub fn new(vars: Vec<(String, Option<Box<dyn Expr>>)>, body: Box<dyn Expr>) -> VarExprAst {
+        let var_types = vars.iter().map(|_| TypeSlot::default()).collect();
+        VarExprAst {
+            vars,
+            body,
+            var_types,
+        }
+    }
+}
+impl Expr for VarExprAst {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
         unsafe {
-            // convert rust *const u8 pointer to C-compatible *const i8 pointer
-            let name = (self.callee.clone() + "\0").into_bytes();
-            let ptr = name.as_ptr() as *const i8;
-            let callee_nf = LLVMGetNamedFunction(codegen_context.module, ptr);
-            let callee_t = LLVMGlobalGetValueType(callee_nf);
-            let args_v: Vec<Result<LLVMValueRef, String>> = self
-                .args
-                .iter()
-                .map(|expr| expr.generate_code(codegen_context))
-                .collect();
-            for arg in args_v.iter() {
-                match arg {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(e.clone());
-                    }
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let mut shadowed_values = vec![];
+            for ((name, init), var_type) in self.vars.iter().zip(self.var_types.iter()) {
+                log_verbose(format!("Generate var {:?}", name));
+                // the initializer is evaluated before the variable is in scope,
+                // so var a = a in ... refers to an outer a
+                let init_value = match init {
+                    Some(init) => init.generate_code(codegen_context)?,
+                    None => LLVMConstReal(dt, 0.0),
+                };
+                // variables of an inferred type hold the converted initializer
+                let var_t = match &*var_type.borrow() {
+                    Some(ty) => ty.llvm_type(codegen_context)?,
+                    None => LLVMTypeOf(init_value),
+                };
+                let Some(init_value) = build_conversion(codegen_context, init_value, var_t) else {
+                    return Err(format!(
+                        "Cannot assign a {} to a {} variable",
+                        type_name(LLVMTypeOf(init_value)),
+                        type_name(var_t)
+                    ));
+                };
+                let alloca = create_entry_block_alloca(codegen_context, name, var_t);
+                LLVMBuildStore(codegen_context.ir_builder, init_value, alloca);
+                shadowed_values.push((
+                    name,
+                    codegen_context.named_values.insert(name.clone(), alloca),
+                ));
+            }
+
+            let body_value = self.body.generate_code(codegen_context)?;
+
+            // restore the bindings in reverse order, in case a name is declared twice
+            for (name, shadowed_value) in shadowed_values.into_iter().rev() {
+                match shadowed_value {
+                    Some(value) => codegen_context.named_values.insert(name.clone(), value),
+                    None => codegen_context.named_values.remove(name),
                 };
             }
-            let mut args_v: Vec<LLVMValueRef> =
-                args_v.into_iter().map(|val| val.unwrap()).collect();
+            Ok(body_value)
+        }
+    }
 
-            log_verbose(format!("Generate function call {:?}", self.callee));
-            Ok(LLVMBuildCall2(
-                codegen_context.ir_builder,
-                callee_t,
-                callee_nf,
-                args_v.as_mut_ptr(),
-                args_v.len() as u32,
-                ptr,
-            ))
+    fn infer(&self, inference: &mut Inference) -> Result<Term, String> {
+        let mut shadowed = vec![];
+        for ((name, init), var_type) in self.vars.iter().zip(self.var_types.iter()) {
+            let term = match init {
+                Some(init) => init.infer(inference)?,
+                None => Term::Double,
+            };
+            inference.declare_variable(&term);
+            inference.defer(term.clone(), var_type.clone());
+            shadowed.push((name, inference.locals.insert(name.clone(), term)));
+        }
+
+        let body = self.body.infer(inference)?;
+
+        for (name, term) in shadowed.into_iter().rev() {
+            match term {
+                Some(term) => inference.locals.insert(name.clone(), term),
+                None => inference.locals.remove(name),
+            };
         }
+        Ok(body)
+    }
+
+    fn mark_tail_calls(&self, name: &str) -> bool {
+        self.body.mark_tail_calls(name)
     }
 }
 
 pub trait Function {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String>;
+
+    // infers the types of the item and registers them for the following items
+    fn infer_types(&mut self, type_env: &mut TypeEnv) -> Result<(), String>;
+
+    // function definitions are declared before the other items are compiled
+    fn as_definition(&mut self) -> Option<&mut FunctionAst> {
+        None
+    }
+
+    // globals are inferred after the definitions and globals they refer to
+    fn as_global(&mut self) -> Option<&mut GlobalVariableAst> {
+        None
+    }
 }
 
 pub struct PrototypeAst {
     pub name: String,
     pub args: Vec<String>,
+    // types that are not annotated are inferred, or double if nothing determines them
+    pub arg_types: Vec<Option<Type>>,
+    pub return_type: Option<Type>,
+    pub pos: Position,
+    // extern taking variable arguments after its parameters like printf of C
+    pub is_variadic: bool,
 }
 impl PrototypeAst {
-    pub fn new(name: String, args: Vec<String>) -> PrototypeAst {
-        PrototypeAst { name, args }
+    pub fn new(
+        name: String,
+        args: Vec<String>,
+        arg_types: Vec<Option<Type>>,
+        return_type: Option<Type>,
+        pos: Position,
+    ) -> PrototypeAst {
+        PrototypeAst {
+            name,
+            args,
+            arg_types,
+            return_type,
+            pos,
+            is_variadic: false,
+        }
+    }
+
+    // registers the signature once all types are known
+    fn register(&self, type_env: &mut TypeEnv) {
+        let arg_types = self
+            .arg_types
+            .iter()
+            .map(|ty| ty.clone().unwrap_or(Type::Double))
+            .collect();
+        let return_type = self.return_type.clone().unwrap_or(Type::Double);
+        type_env
+            .functions
+            .insert(self.name.clone(), (arg_types, return_type));
+        if self.is_variadic {
+            type_env.variadic.insert(self.name.clone());
+        } else {
+            type_env.variadic.remove(&self.name);
+        }
     }
 }
 impl Function for PrototypeAst {
     fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
         unsafe {
-            let dt = LLVMDoubleTypeInContext(codegen_context.context);
-            let mut args_t: Vec<llvm::prelude::LLVMTypeRef> =
-                self.args.iter().map(|_| dt).collect();
-            let ft = LLVMFunctionType(
-                dt,                  // return type
-                args_t.as_mut_ptr(), // argument types
-                args_t.len() as u32,
-                false as i32, // whether the function is variadic
-            );
+            let args_t = self
+                .arg_types
+                .iter()
+                .map(|ty| {
+                    ty.clone()
+                        .unwrap_or(Type::Double)
+                        .llvm_type(codegen_context)
+                })
+                .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+            let return_t = self
+                .return_type
+                .clone()
+                .unwrap_or(Type::Double)
+                .llvm_type(codegen_context)?;
+            let ft = lower_function_type(codegen_context, &args_t, return_t, self.is_variadic);
             let name = (self.name.clone() + "\0").into_bytes();
             let ptr = name.as_ptr() as *const i8;
 
+            // a function that is only declared so far, e.g. by an extern, gets defined
+            let existing = LLVMGetNamedFunction(codegen_context.module, ptr);
+            if !self.name.is_empty()
+                && !existing.is_null()
+                && LLVMIsDeclaration(existing) != 0
+                && LLVMGlobalGetValueType(existing) == ft
+            {
+                return Ok(existing);
+            }
+
             log_verbose(format!("Generate function prototype {:?}", self.name));
             let result = LLVMAddFunction(codegen_context.module, ptr, ft);
+            add_abi_attributes(codegen_context, result, &args_t, return_t, false);
+            // the signature is needed to call the function, llvm renames the function if its
+            // name is taken
+            let mut args_t = args_t;
+            codegen_context.function_types.insert(
+                value_name(result),
+                LLVMFunctionType(
+                    return_t,
+                    args_t.as_mut_ptr(),
+                    args_t.len() as u32,
+                    self.is_variadic as i32,
+                ),
+            );
 
-            // set function parameter names
+            // set function parameter names, a struct returned in memory is the first parameter
+            let first_arg = LLVMCountParams(result) as usize - args_t.len();
             for i in 0..args_t.len() {
-                let param = LLVMGetParam(result, i as u32);
+                let param = LLVMGetParam(result, (first_arg + i) as u32);
                 let param_name = (self.args.get(i).unwrap().clone()).into_bytes();
                 LLVMSetValueName2(param, param_name.as_ptr() as *const i8, param_name.len());
             }
@@ -203,6 +2557,12 @@ impl Function for PrototypeAst {
             Ok(result)
         }
     }
+
+    fn infer_types(&mut self, type_env: &mut TypeEnv) -> Result<(), String> {
+        // externs have no body to infer their types from
+        self.register(type_env);
+        Ok(())
+    }
 }
 
 pub struct FunctionAst {
@@ -213,18 +2573,14 @@ impl FunctionAst {
     pub fn new(proto: PrototypeAst, body: Box<dyn Expr>) -> FunctionAst {
         FunctionAst { proto, body }
     }
-}
-impl Function for FunctionAst {
-    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+
+    // generates the body into the function declared by the prototype
+    pub fn generate_body(
+        &self,
+        codegen_context: &mut CodeGenContext,
+        function: LLVMValueRef,
+    ) -> Result<LLVMValueRef, String> {
         unsafe {
-            let function = self.proto.generate_code(codegen_context);
-            match function {
-                Ok(_) => {}
-                Err(e) => {
-                    return Err(e.clone());
-                }
-            };
-            let function = function.unwrap();
             codegen_context.current_function = Some(function);
 
             //let ptr = self.proto.name.as_ptr() as *const i8;
@@ -234,29 +2590,69 @@ impl Function for FunctionAst {
             // insert instructions into the end of the basic block
             LLVMPositionBuilderAtEnd(codegen_context.ir_builder, bb);
 
+            // parameters are stored in allocas so that they can be assigned to
             codegen_context.named_values.clear();
+            codegen_context.loop_targets.clear();
+            let (params_t, return_t) = function_signature(codegen_context, function);
+            let first_arg = LLVMCountParams(function) as usize - params_t.len();
             for i in 0..self.proto.args.len() {
-                match self.proto.args.get(i) {
-                    Some(name) => codegen_context.named_values.insert(name.clone(), i as u32),
-                    None => {
+                match (self.proto.args.get(i), params_t.get(i)) {
+                    (Some(name), Some(param_t)) => {
+                        let param = LLVMGetParam(function, (first_arg + i) as u32);
+                        let param = build_param_value(codegen_context, param, *param_t);
+                        let alloca = create_entry_block_alloca(codegen_context, name, *param_t);
+                        LLVMBuildStore(codegen_context.ir_builder, param, alloca);
+                        codegen_context.named_values.insert(name.clone(), alloca)
+                    }
+                    _ => {
                         codegen_context.current_function = None;
-                        LLVMDeleteFunction(function);
+                        discard_function(codegen_context, function);
                         return Err(String::from("Invalid function param"));
                     }
                 };
             }
 
+            // recursive tail calls jump to a block after the parameters are stored
+            codegen_context.tail_recursion = None;
+            if !self.proto.name.is_empty() && self.body.mark_tail_calls(&self.proto.name) {
+                let body_bb = LLVMAppendBasicBlockInContext(
+                    codegen_context.context,
+                    function,
+                    c"body".as_ptr(),
+                );
+                LLVMBuildBr(codegen_context.ir_builder, body_bb);
+                LLVMPositionBuilderAtEnd(codegen_context.ir_builder, body_bb);
+                let params = self
+                    .proto
+                    .args
+                    .iter()
+                    .map(|name| codegen_context.named_values[name])
+                    .collect();
+                codegen_context.tail_recursion = Some(TailRecursion { body_bb, params });
+            }
+
             let return_value = self.body.generate_code(codegen_context);
             match return_value {
                 Ok(_) => {}
                 Err(e) => {
                     codegen_context.current_function = None;
-                    LLVMDeleteFunction(function);
+                    discard_function(codegen_context, function);
                     return Err(e.clone());
                 }
             };
             let return_value = return_value.unwrap();
-            LLVMBuildRet(codegen_context.ir_builder, return_value);
+            let Some(return_value) = build_conversion(codegen_context, return_value, return_t)
+            else {
+                codegen_context.current_function = None;
+                discard_function(codegen_context, function);
+                return Err(format!(
+                    "Function {} must return a {}, found {}",
+                    self.proto.name,
+                    type_name(return_t),
+                    type_name(LLVMTypeOf(return_value))
+                ));
+            };
+            build_return(codegen_context, return_value);
             LLVMVerifyFunction(
                 function,
                 llvm_sys::analysis::LLVMVerifierFailureAction::LLVMPrintMessageAction,
@@ -272,4 +2668,254 @@ impl Function for FunctionAst {
             Ok(function)
         }
     }
+
+    // definitions and globals the body uses that are declared but not inferred yet, found by
+    // inferring the types of the body without keeping them
+    pub fn references(&self, type_env: &TypeEnv) -> HashSet<String> {
+        let mut inference = Inference::new(type_env);
+        let params: Vec<Term> = self
+            .proto
+            .arg_types
+            .iter()
+            .map(|ty| inference.annotated(ty))
+            .collect();
+        let ret = inference.annotated(&self.proto.return_type);
+        for (name, param) in self.proto.args.iter().zip(params.iter()) {
+            inference.locals.insert(name.clone(), param.clone());
+        }
+        inference
+            .current
+            .insert(self.proto.name.clone(), (params, ret));
+        // type errors are reported when the types are inferred
+        let _ = self.body.infer(&mut inference);
+        inference.references
+    }
+
+    // infers the types of definitions that call each other together, so that the types of
+    // their parameters and return values are determined by all of them
+    pub fn infer_group(
+        group: &mut [&mut FunctionAst],
+        type_env: &mut TypeEnv,
+    ) -> Result<(), String> {
+        let mut inference = Inference::new(type_env);
+        let mut signatures = vec![];
+        for function in group.iter() {
+            let proto = &function.proto;
+            let params: Vec<Term> = proto
+                .arg_types
+                .iter()
+                .map(|ty| inference.annotated(ty))
+                .collect();
+            let ret = inference.annotated(&proto.return_type);
+            inference
+                .current
+                .insert(proto.name.clone(), (params.clone(), ret.clone()));
+            signatures.push((params, ret));
+        }
+        for (function, (params, ret)) in group.iter().zip(signatures.iter()) {
+            inference.locals.clear();
+            for (name, param) in function.proto.args.iter().zip(params.iter()) {
+                inference.locals.insert(name.clone(), param.clone());
+            }
+            let body = function.body.infer(&mut inference)?;
+            inference.coerce(ret, &body, function.proto.pos)?;
+        }
+        inference.finish();
+
+        let mut types = vec![];
+        for (function, (params, ret)) in group.iter().zip(signatures.iter()) {
+            let proto = &function.proto;
+            let arg_types: Vec<Option<Type>> = params
+                .iter()
+                .map(|param| Some(inference.to_type(param)))
+                .collect();
+            let return_type = inference.to_type(ret);
+            log_verbose(format!(
+                "Inferred {}({:?}): {:?}",
+                proto.name, arg_types, return_type
+            ));
+            types.push((arg_types, return_type));
+        }
+        for (function, (arg_types, return_type)) in group.iter_mut().zip(types) {
+            function.proto.arg_types = arg_types;
+            function.proto.return_type = Some(return_type);
+            function.proto.register(type_env);
+        }
+        Ok(())
+    }
+}
+
+// removes a function whose body could not be generated, calls generated before refer to a
+// declaration of the same name instead
+fn discard_function(codegen_context: &mut CodeGenContext, function: LLVMValueRef) {
+    unsafe {
+        if LLVMGetFirstUse(function).is_null() {
+            LLVMDeleteFunction(function);
+            return;
+        }
+        let name = value_name(function);
+        let declaration = LLVMAddFunction(
+            codegen_context.module,
+            c"".as_ptr(),
+            LLVMGlobalGetValueType(function),
+        );
+        LLVMReplaceAllUsesWith(function, declaration);
+        LLVMDeleteFunction(function);
+        LLVMSetValueName2(declaration, name.as_ptr() as *const i8, name.len());
+    }
+}
+
+impl Function for FunctionAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        let function = self.proto.generate_code(codegen_context)?;
+        self.generate_body(codegen_context, function)
+    }
+
+    fn infer_types(&mut self, type_env: &mut TypeEnv) -> Result<(), String> {
+        FunctionAst::infer_group(&mut [self], type_env)
+    }
+
+    fn as_definition(&mut self) -> Option<&mut FunctionAst> {
+        Some(self)
+    }
+}
+
+// top-level global and const declarations
+pub struct GlobalVariableAst {
+    pub name: String,
+    pub init: Box<dyn Expr>,
+    pub is_constant: bool,
+}
+impl GlobalVariableAst {
+    pub fn new(name: String, init: Box<dyn Expr>, is_constant: bool) -> GlobalVariableAst {
+        GlobalVariableAst {
+            name,
+            init,
+            is_constant,
+        }
+    }
+
+    // definitions and globals the initializer uses that are declared but not inferred yet
+    pub fn references(&self, type_env: &TypeEnv) -> HashSet<String> {
+        let mut inference = Inference::new(type_env);
+        // type errors are reported when the types are inferred
+        let _ = self.init.infer(&mut inference);
+        inference.references
+    }
+}
+impl Function for GlobalVariableAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let name = (self.name.clone() + "\0").into_bytes();
+            let ptr = name.as_ptr() as *const i8;
+            if !LLVMGetNamedGlobal(codegen_context.module, ptr).is_null()
+                || !LLVMGetNamedFunction(codegen_context.module, ptr).is_null()
+            {
+                return Err(format!("Redefinition of {}", self.name));
+            }
+
+            // the initializer is generated into a temporary function, the builder folds
+            // constant expressions so that anything else is rejected
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let ft = LLVMFunctionType(dt, std::ptr::null_mut(), 0, false as i32);
+            let init_function = LLVMAddFunction(codegen_context.module, c"globalinit".as_ptr(), ft);
+            let bb = LLVMAppendBasicBlockInContext(
+                codegen_context.context,
+                init_function,
+                c"entry".as_ptr(),
+            );
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, bb);
+            codegen_context.current_function = Some(init_function);
+            codegen_context.named_values.clear();
+            codegen_context.loop_targets.clear();
+
+            let init_value = self.init.generate_code(codegen_context);
+            codegen_context.current_function = None;
+            LLVMDeleteFunction(init_function);
+            let init_value = init_value?;
+            if LLVMIsConstant(init_value) == 0 {
+                return Err(format!(
+                    "Initializer of {} is not a constant expression",
+                    self.name
+                ));
+            }
+
+            log_verbose(format!("Generate global {:?}", self.name));
+            let global = LLVMAddGlobal(codegen_context.module, LLVMTypeOf(init_value), ptr);
+            LLVMSetInitializer(global, init_value);
+            LLVMSetGlobalConstant(global, self.is_constant as i32);
+            Ok(global)
+        }
+    }
+
+    fn infer_types(&mut self, type_env: &mut TypeEnv) -> Result<(), String> {
+        type_env.declared_globals.remove(&self.name);
+        let mut inference = Inference::new(type_env);
+        let init = self.init.infer(&mut inference)?;
+        inference.finish();
+        let init = inference.to_final_term(&init);
+        type_env.globals.insert(self.name.clone(), init);
+        Ok(())
+    }
+
+    fn as_global(&mut self) -> Option<&mut GlobalVariableAst> {
+        Some(self)
+    }
+}
+
+// top-level struct declarations
+pub struct StructAst {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+}
+impl StructAst {
+    pub fn new(name: String, fields: Vec<(String, Type)>) -> StructAst {
+        StructAst { name, fields }
+    }
+}
+impl Function for StructAst {
+    fn generate_code(&self, codegen_context: &mut CodeGenContext) -> Result<LLVMValueRef, String> {
+        unsafe {
+            let name = (self.name.clone() + "\0").into_bytes();
+            let ptr = name.as_ptr() as *const i8;
+            if codegen_context.struct_types.contains_key(&self.name)
+                || !LLVMGetNamedFunction(codegen_context.module, ptr).is_null()
+            {
+                return Err(format!("Redefinition of {}", self.name));
+            }
+
+            let mut fields_t = self
+                .fields
+                .iter()
+                .map(|(_, ty)| ty.llvm_type(codegen_context))
+                .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+
+            log_verbose(format!("Generate struct {:?}", self.name));
+            let struct_t = LLVMStructCreateNamed(codegen_context.context, ptr);
+            LLVMStructSetBody(
+                struct_t,
+                fields_t.as_mut_ptr(),
+                fields_t.len() as u32,
+                false as i32,
+            );
+            codegen_context.struct_types.insert(
+                self.name.clone(),
+                StructDefinition {
+                    ty: struct_t,
+                    fields: self.fields.iter().map(|(name, _)| name.clone()).collect(),
+                },
+            );
+            // a declaration has no value
+            Ok(LLVMGetUndef(struct_t))
+        }
+    }
+
+    fn infer_types(&mut self, type_env: &mut TypeEnv) -> Result<(), String> {
+        // a redefinition is rejected by the code generation
+        type_env
+            .structs
+            .entry(self.name.clone())
+            .or_insert_with(|| self.fields.clone());
+        Ok(())
+    }
 }
@@ -0,0 +1,134 @@
+extern crate llvm_sys as llvm;
+use crate::{abi::*, codegen::*, logger::*};
+use llvm::LLVMLinkage;
+use llvm::core::*;
+use llvm::prelude::LLVMTypeRef;
+use llvm::prelude::LLVMValueRef;
+
+// Function values are closures, a struct of a pointer to their code and a pointer to an
+// environment record holding the variables they captured. The code takes the environment as
+// its first parameter, so that lambdas and named functions are called the same way. C hosts
+// see a closure as struct { ret (*code)(void *env, ...); void *env; }.
+
+// environments are untyped pointers, the code of a lambda casts it to its record type
+pub fn env_type(codegen_context: &CodeGenContext) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(LLVMInt8TypeInContext(codegen_context.context), 0) }
+}
+
+// parameter types of the code of a closure with the given parameter types
+pub fn closure_code_params(
+    codegen_context: &CodeGenContext,
+    params_t: &[LLVMTypeRef],
+) -> Vec<LLVMTypeRef> {
+    let mut code_params_t = vec![env_type(codegen_context)];
+    code_params_t.extend_from_slice(params_t);
+    code_params_t
+}
+
+pub fn closure_type(
+    codegen_context: &CodeGenContext,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+) -> LLVMTypeRef {
+    unsafe {
+        let code_params_t = closure_code_params(codegen_context, params_t);
+        let code_t = lower_function_type(codegen_context, &code_params_t, return_t, false);
+        let mut elements_t = [LLVMPointerType(code_t, 0), env_type(codegen_context)];
+        LLVMStructTypeInContext(
+            codegen_context.context,
+            elements_t.as_mut_ptr(),
+            elements_t.len() as u32,
+            false as i32,
+        )
+    }
+}
+
+// adds the internal function for the code of a closure with the given parameter and return
+// types, its first parameter after an sret pointer is the environment
+pub fn add_closure_code(
+    codegen_context: &mut CodeGenContext,
+    name: &str,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+) -> LLVMValueRef {
+    unsafe {
+        let mut code_params_t = closure_code_params(codegen_context, params_t);
+        let ft = lower_function_type(codegen_context, &code_params_t, return_t, false);
+        let name = (name.to_string() + "\0").into_bytes();
+        let code = LLVMAddFunction(codegen_context.module, name.as_ptr() as *const i8, ft);
+        LLVMSetLinkage(code, LLVMLinkage::LLVMInternalLinkage);
+        add_abi_attributes(codegen_context, code, &code_params_t, return_t, false);
+        codegen_context.function_types.insert(
+            value_name(code),
+            LLVMFunctionType(
+                return_t,
+                code_params_t.as_mut_ptr(),
+                code_params_t.len() as u32,
+                false as i32,
+            ),
+        );
+        code
+    }
+}
+
+pub fn build_closure(
+    codegen_context: &mut CodeGenContext,
+    code: LLVMValueRef,
+    env: LLVMValueRef,
+) -> LLVMValueRef {
+    unsafe {
+        let (code_params_t, return_t) = function_signature(codegen_context, code);
+        let closure_t = closure_type(codegen_context, &code_params_t[1..], return_t);
+        let closure = LLVMBuildInsertValue(
+            codegen_context.ir_builder,
+            LLVMGetUndef(closure_t),
+            LLVMBuildPointerCast(
+                codegen_context.ir_builder,
+                code,
+                LLVMStructGetTypeAtIndex(closure_t, 0),
+                c"code".as_ptr(),
+            ),
+            0,
+            c"closure".as_ptr(),
+        );
+        LLVMBuildInsertValue(
+            codegen_context.ir_builder,
+            closure,
+            env,
+            1,
+            c"closure".as_ptr(),
+        )
+    }
+}
+
+// closure of a named function, its code is a wrapper that ignores the environment
+pub fn build_function_closure(
+    codegen_context: &mut CodeGenContext,
+    function: LLVMValueRef,
+) -> LLVMValueRef {
+    unsafe {
+        let name = value_name(function) + ".closure";
+        let c_name = (name.clone() + "\0").into_bytes();
+        let mut code = LLVMGetNamedFunction(codegen_context.module, c_name.as_ptr() as *const i8);
+        if code.is_null() {
+            log_verbose(format!("Generate closure code {:?}", name));
+            let (params_t, return_t) = function_signature(codegen_context, function);
+            code = add_closure_code(codegen_context, &name, &params_t, return_t);
+            let enclosing = enter_function(codegen_context, code);
+            let first_arg = LLVMCountParams(code) as usize - params_t.len();
+            let args: Vec<LLVMValueRef> = params_t
+                .iter()
+                .enumerate()
+                .map(|(i, param_t)| {
+                    let param = LLVMGetParam(code, (first_arg + i) as u32);
+                    build_param_value(codegen_context, param, *param_t)
+                })
+                .collect();
+            let result = build_lowered_call(codegen_context, function, &args, c"result");
+            build_return(codegen_context, result);
+            leave_function(codegen_context, enclosing);
+        }
+        let env = LLVMConstNull(env_type(codegen_context));
+        build_closure(codegen_context, code, env)
+    }
+}
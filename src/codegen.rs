@@ -1,12 +1,15 @@
 extern crate llvm_sys as llvm;
-use crate::{ast::*, logger::*};
+use crate::{abi::*, ast::*, closure::*, logger::*};
 use std::collections::HashMap;
 
 use llvm::core::*;
+use llvm::prelude::LLVMBasicBlockRef;
 use llvm::prelude::LLVMBuilderRef;
 use llvm::prelude::LLVMContextRef;
 use llvm::prelude::LLVMModuleRef;
+use llvm::prelude::LLVMTypeRef;
 use llvm::prelude::LLVMValueRef;
+use llvm::{LLVMIntPredicate, LLVMLinkage, LLVMRealPredicate, LLVMTypeKind, LLVMUnnamedAddr};
 use llvm_sys::target::*;
 use llvm_sys::target_machine::LLVMAddAnalysisPasses;
 use llvm_sys::target_machine::LLVMCodeGenFileType;
@@ -16,21 +19,65 @@ use llvm_sys::target_machine::LLVMGetTargetFromTriple;
 use llvm_sys::target_machine::LLVMTarget;
 use llvm_sys::target_machine::LLVMTargetMachineEmitToFile;
 use llvm_sys::target_machine::LLVMTargetRef;
+use llvm_sys::transforms::pass_builder::*;
+
+// jump targets of break and continue inside a loop body
+pub struct LoopTargets {
+    pub continue_bb: LLVMBasicBlockRef,
+    pub break_bb: LLVMBasicBlockRef,
+}
+
+// a call of the function being generated in tail position stores its arguments in the
+// parameters and jumps back to the start of the body, so that the recursion is a loop
+pub struct TailRecursion {
+    pub body_bb: LLVMBasicBlockRef,
+    pub params: Vec<LLVMValueRef>,
+}
+
+// named LLVM struct type of a struct declaration and the names of its fields
+pub struct StructDefinition {
+    pub ty: LLVMTypeRef,
+    pub fields: Vec<String>,
+}
+
+// settings given on the command line
+#[derive(Default, Clone)]
+pub struct CodeGenOptions {
+    // assert expressions are not evaluated and always evaluate to 0.0
+    pub strip_asserts: bool,
+}
 
 pub struct CodeGenContext {
     pub context: LLVMContextRef,
     pub module: LLVMModuleRef,
     pub ir_builder: LLVMBuilderRef,
-    pub named_values: HashMap<String, u32>,
+    pub named_values: HashMap<String, LLVMValueRef>,
     pub current_function: Option<LLVMValueRef>,
+    pub loop_targets: Vec<LoopTargets>,
+    pub tail_recursion: Option<TailRecursion>,
+    pub struct_types: HashMap<String, StructDefinition>,
+    // function types of the prototypes before they were lowered to the calling convention
+    pub function_types: HashMap<String, LLVMTypeRef>,
+    // structs in signatures are only lowered on targets using the calling convention of
+    // x86-64 System V
+    pub lowers_structs: bool,
+    pub options: CodeGenOptions,
 }
 
-pub fn create_context() -> CodeGenContext {
+// state of the code generation in a function while a nested function is generated
+pub struct EnclosingFunction {
+    insert_bb: LLVMBasicBlockRef,
+    function: Option<LLVMValueRef>,
+    named_values: HashMap<String, LLVMValueRef>,
+    loop_targets: Vec<LoopTargets>,
+}
+
+pub fn create_context(options: CodeGenOptions, module_name: &str) -> CodeGenContext {
     unsafe {
         log_verbose("Create code gen context".to_string());
         let context: LLVMContextRef = LLVMContextCreate();
-        let module_id = c"module".as_ptr();
-        let module = LLVMModuleCreateWithNameInContext(module_id, context);
+        let module_id = (module_name.to_string() + "\0").into_bytes();
+        let module = LLVMModuleCreateWithNameInContext(module_id.as_ptr() as *const i8, context);
         let ir_builder = LLVMCreateBuilderInContext(context);
 
         CodeGenContext {
@@ -39,11 +86,501 @@ pub fn create_context() -> CodeGenContext {
             ir_builder,
             named_values: HashMap::new(),
             current_function: None,
+            loop_targets: vec![],
+            tail_recursion: None,
+            struct_types: HashMap::new(),
+            function_types: HashMap::new(),
+            lowers_structs: is_system_v_target(),
+            options,
+        }
+    }
+}
+
+// stack slot for a local variable, placed in the entry block of the current function
+// so that the mem2reg pass can promote it to a register
+pub fn create_entry_block_alloca(
+    codegen_context: &mut CodeGenContext,
+    name: &str,
+    ty: LLVMTypeRef,
+) -> LLVMValueRef {
+    unsafe {
+        let function = codegen_context.current_function.unwrap();
+        let entry_bb = LLVMGetEntryBasicBlock(function);
+        let builder = LLVMCreateBuilderInContext(codegen_context.context);
+        let first_instruction = LLVMGetFirstInstruction(entry_bb);
+        if first_instruction.is_null() {
+            LLVMPositionBuilderAtEnd(builder, entry_bb);
+        } else {
+            LLVMPositionBuilderBefore(builder, first_instruction);
+        }
+        let name = (name.to_string() + "\0").into_bytes();
+        let alloca = LLVMBuildAlloca(builder, ty, name.as_ptr() as *const i8);
+        LLVMDisposeBuilder(builder);
+        alloca
+    }
+}
+
+// continues the code generation in the entry block of a new function without locals,
+// leave_function returns to the function that was generated before
+pub fn enter_function(
+    codegen_context: &mut CodeGenContext,
+    function: LLVMValueRef,
+) -> EnclosingFunction {
+    unsafe {
+        let enclosing = EnclosingFunction {
+            insert_bb: LLVMGetInsertBlock(codegen_context.ir_builder),
+            function: codegen_context.current_function.replace(function),
+            named_values: std::mem::take(&mut codegen_context.named_values),
+            loop_targets: std::mem::take(&mut codegen_context.loop_targets),
+        };
+        let entry_bb =
+            LLVMAppendBasicBlockInContext(codegen_context.context, function, c"entry".as_ptr());
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, entry_bb);
+        enclosing
+    }
+}
+
+pub fn leave_function(codegen_context: &mut CodeGenContext, enclosing: EnclosingFunction) {
+    unsafe {
+        if enclosing.insert_bb.is_null() {
+            LLVMClearInsertionPosition(codegen_context.ir_builder);
+        } else {
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, enclosing.insert_bb);
+        }
+    }
+    codegen_context.current_function = enclosing.function;
+    codegen_context.named_values = enclosing.named_values;
+    codegen_context.loop_targets = enclosing.loop_targets;
+}
+
+// int is a 32 bit signed integer like the int of C
+pub fn int_type(codegen_context: &CodeGenContext) -> LLVMTypeRef {
+    unsafe { LLVMInt32TypeInContext(codegen_context.context) }
+}
+
+pub fn bool_type(codegen_context: &CodeGenContext) -> LLVMTypeRef {
+    unsafe { LLVMInt1TypeInContext(codegen_context.context) }
+}
+
+// strings are pointers to null-terminated character arrays
+pub fn string_type(codegen_context: &CodeGenContext) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(LLVMInt8TypeInContext(codegen_context.context), 0) }
+}
+
+const ARRAY_TYPE_NAME: &str = "kalei.array";
+
+// arrays are passed by value as a struct of their length and a pointer to the elements
+pub fn array_type(codegen_context: &CodeGenContext) -> LLVMTypeRef {
+    unsafe {
+        let name = (ARRAY_TYPE_NAME.to_string() + "\0").into_bytes();
+        let ptr = name.as_ptr() as *const i8;
+        let array_t = LLVMGetTypeByName2(codegen_context.context, ptr);
+        if !array_t.is_null() {
+            return array_t;
+        }
+        let array_t = LLVMStructCreateNamed(codegen_context.context, ptr);
+        let mut elements_t = [
+            LLVMInt64TypeInContext(codegen_context.context),
+            LLVMPointerType(LLVMDoubleTypeInContext(codegen_context.context), 0),
+        ];
+        LLVMStructSetBody(
+            array_t,
+            elements_t.as_mut_ptr(),
+            elements_t.len() as u32,
+            false as i32,
+        );
+        array_t
+    }
+}
+
+// private constant array in the module holding the null-terminated string
+pub fn create_global_string(codegen_context: &mut CodeGenContext, val: &str) -> LLVMValueRef {
+    unsafe {
+        let init = LLVMConstStringInContext2(
+            codegen_context.context,
+            val.as_ptr() as *const i8,
+            val.len(),
+            false as i32, // add the null terminator
+        );
+        let global = LLVMAddGlobal(codegen_context.module, LLVMTypeOf(init), c"str".as_ptr());
+        LLVMSetInitializer(global, init);
+        LLVMSetGlobalConstant(global, true as i32);
+        LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+        LLVMSetUnnamedAddress(global, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+        LLVMConstPointerCast(global, string_type(codegen_context))
+    }
+}
+
+// name of a value type for error messages
+pub fn type_name(ty: LLVMTypeRef) -> String {
+    unsafe {
+        match LLVMGetTypeKind(ty) {
+            LLVMTypeKind::LLVMDoubleTypeKind => String::from("double"),
+            LLVMTypeKind::LLVMIntegerTypeKind if LLVMGetIntTypeWidth(ty) == 32 => {
+                String::from("int")
+            }
+            LLVMTypeKind::LLVMIntegerTypeKind if LLVMGetIntTypeWidth(ty) == 1 => {
+                String::from("bool")
+            }
+            LLVMTypeKind::LLVMPointerTypeKind => String::from("string"),
+            LLVMTypeKind::LLVMStructTypeKind if !LLVMGetStructName(ty).is_null() => {
+                match struct_name(ty) {
+                    name if name == ARRAY_TYPE_NAME => String::from("array"),
+                    name => name,
+                }
+            }
+            _ => {
+                let message = LLVMPrintTypeToString(ty);
+                let name = std::ffi::CStr::from_ptr(message)
+                    .to_string_lossy()
+                    .into_owned();
+                LLVMDisposeMessage(message);
+                name
+            }
+        }
+    }
+}
+
+pub fn value_name(value: LLVMValueRef) -> String {
+    unsafe {
+        let mut len = 0;
+        let ptr = LLVMGetValueName2(value, &mut len);
+        String::from_utf8_lossy(std::slice::from_raw_parts(ptr as *const u8, len)).into_owned()
+    }
+}
+
+pub fn struct_name(ty: LLVMTypeRef) -> String {
+    unsafe {
+        std::ffi::CStr::from_ptr(LLVMGetStructName(ty))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+// declaration of the struct type of the value, if it is a struct declared by the program
+pub fn get_struct_definition(
+    codegen_context: &CodeGenContext,
+    ty: LLVMTypeRef,
+) -> Option<&StructDefinition> {
+    unsafe {
+        if LLVMGetTypeKind(ty) != LLVMTypeKind::LLVMStructTypeKind
+            || LLVMGetStructName(ty).is_null()
+        {
+            return None;
+        }
+    }
+    codegen_context
+        .struct_types
+        .get(&struct_name(ty))
+        .filter(|definition| definition.ty == ty)
+}
+
+pub fn is_double(value: LLVMValueRef) -> bool {
+    unsafe { LLVMGetTypeKind(LLVMTypeOf(value)) == LLVMTypeKind::LLVMDoubleTypeKind }
+}
+
+pub fn is_int(value: LLVMValueRef) -> bool {
+    unsafe {
+        let ty = LLVMTypeOf(value);
+        LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMIntegerTypeKind && LLVMGetIntTypeWidth(ty) == 32
+    }
+}
+
+pub fn is_bool(value: LLVMValueRef) -> bool {
+    unsafe {
+        let ty = LLVMTypeOf(value);
+        LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMIntegerTypeKind && LLVMGetIntTypeWidth(ty) == 1
+    }
+}
+
+// doubles, ints and bools are numbers
+pub fn is_number(value: LLVMValueRef) -> bool {
+    is_double(value) || is_int(value) || is_bool(value)
+}
+
+// int, bool or double constant without a fractional part like a number literal written as an
+// integer, which can be used as an int
+pub fn is_integral(value: LLVMValueRef) -> bool {
+    unsafe {
+        if !is_double(value) {
+            return is_number(value);
+        }
+        if LLVMIsAConstantFP(value).is_null() {
+            return false;
+        }
+        let mut loses_info = 0;
+        let value = LLVMConstRealGetDouble(value, &mut loses_info);
+        value.fract() == 0.0 && value.abs() <= i32::MAX as f64
+    }
+}
+
+// type both numbers are converted to when they are combined by an operator: an integral double
+// constant becomes an int when combined with an int, other doubles turn ints into doubles
+pub fn common_number_type(
+    codegen_context: &CodeGenContext,
+    lhs: LLVMValueRef,
+    rhs: LLVMValueRef,
+) -> LLVMTypeRef {
+    unsafe {
+        if LLVMTypeOf(lhs) == LLVMTypeOf(rhs) {
+            return LLVMTypeOf(lhs);
+        }
+        if is_integral(lhs) && is_integral(rhs) {
+            int_type(codegen_context)
+        } else {
+            LLVMDoubleTypeInContext(codegen_context.context)
+        }
+    }
+}
+
+// implicit conversion between numbers, other values must already have the type
+pub fn build_conversion(
+    codegen_context: &mut CodeGenContext,
+    value: LLVMValueRef,
+    ty: LLVMTypeRef,
+) -> Option<LLVMValueRef> {
+    unsafe {
+        let value_t = LLVMTypeOf(value);
+        if value_t == ty {
+            return Some(value);
+        }
+        if !is_number(value) {
+            return None;
+        }
+        let builder = codegen_context.ir_builder;
+        let name = c"conv".as_ptr();
+        let dt = LLVMDoubleTypeInContext(codegen_context.context);
+        if ty == dt {
+            if is_int(value) {
+                Some(LLVMBuildSIToFP(builder, value, ty, name))
+            } else {
+                Some(LLVMBuildUIToFP(builder, value, ty, name))
+            }
+        } else if ty == int_type(codegen_context) {
+            if is_double(value) {
+                Some(LLVMBuildFPToSI(builder, value, ty, name))
+            } else {
+                Some(LLVMBuildZExt(builder, value, ty, name))
+            }
+        } else if ty == bool_type(codegen_context) {
+            // non-zero numbers are true
+            Some(build_condition(codegen_context, value, c"conv").unwrap())
+        } else {
+            None
+        }
+    }
+}
+
+// i64 of a number, used for array lengths and indices
+pub fn build_size(
+    codegen_context: &mut CodeGenContext,
+    value: LLVMValueRef,
+    name: &std::ffi::CStr,
+) -> LLVMValueRef {
+    unsafe {
+        let i64_t = LLVMInt64TypeInContext(codegen_context.context);
+        if is_double(value) {
+            LLVMBuildFPToSI(codegen_context.ir_builder, value, i64_t, name.as_ptr())
+        } else if is_int(value) {
+            LLVMBuildSExt(codegen_context.ir_builder, value, i64_t, name.as_ptr())
+        } else {
+            LLVMBuildZExt(codegen_context.ir_builder, value, i64_t, name.as_ptr())
+        }
+    }
+}
+
+// i1 that is true if a number is non-zero
+pub fn build_condition(
+    codegen_context: &mut CodeGenContext,
+    value: LLVMValueRef,
+    name: &std::ffi::CStr,
+) -> Result<LLVMValueRef, String> {
+    unsafe {
+        if is_bool(value) {
+            Ok(value)
+        } else if is_int(value) {
+            Ok(LLVMBuildICmp(
+                codegen_context.ir_builder,
+                LLVMIntPredicate::LLVMIntNE,
+                value,
+                LLVMConstInt(int_type(codegen_context), 0, false as i32),
+                name.as_ptr(),
+            ))
+        } else if is_double(value) {
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            Ok(LLVMBuildFCmp(
+                codegen_context.ir_builder,
+                LLVMRealPredicate::LLVMRealONE,
+                value,
+                LLVMConstReal(dt, 0.0),
+                name.as_ptr(),
+            ))
+        } else {
+            Err(format!(
+                "Expected a number as condition, found {}",
+                type_name(LLVMTypeOf(value))
+            ))
         }
     }
 }
 
-pub fn generate_code(codegen_context: &mut CodeGenContext, function: Box<dyn Function>) {
+// call of a function after checking the number and types of the arguments
+pub fn build_call(
+    codegen_context: &mut CodeGenContext,
+    function: LLVMValueRef,
+    args: &mut [LLVMValueRef],
+    name: &str,
+) -> Result<LLVMValueRef, String> {
+    let (params_t, _) = function_signature(codegen_context, function);
+    let is_variadic = unsafe { LLVMIsFunctionVarArg(LLVMGlobalGetValueType(function)) != 0 };
+    if is_variadic && args.len() > params_t.len() {
+        let (fixed_args, variable_args) = args.split_at_mut(params_t.len());
+        convert_args(codegen_context, &params_t, fixed_args, name)?;
+        for arg in variable_args.iter_mut() {
+            *arg = build_argument_promotion(codegen_context, *arg, name)?;
+        }
+    } else {
+        convert_args(codegen_context, &params_t, args, name)?;
+    }
+    let name = (name.to_string() + "\0").into_bytes();
+    Ok(build_lowered_call(
+        codegen_context,
+        function,
+        args,
+        std::ffi::CStr::from_bytes_with_nul(&name).unwrap(),
+    ))
+}
+
+// default argument promotion of C for a variable argument, bools are passed as ints and
+// doubles, ints and strings as they are
+fn build_argument_promotion(
+    codegen_context: &mut CodeGenContext,
+    arg: LLVMValueRef,
+    name: &str,
+) -> Result<LLVMValueRef, String> {
+    unsafe {
+        if is_bool(arg) {
+            let it = int_type(codegen_context);
+            Ok(build_conversion(codegen_context, arg, it).unwrap())
+        } else if is_number(arg) || LLVMTypeOf(arg) == string_type(codegen_context) {
+            Ok(arg)
+        } else {
+            Err(format!(
+                "Variable argument of {} must be a number or a string, found {}",
+                name,
+                type_name(LLVMTypeOf(arg))
+            ))
+        }
+    }
+}
+
+// call of a closure with the given parameter and return types, its code gets the environment
+// before the arguments
+pub fn build_closure_call(
+    codegen_context: &mut CodeGenContext,
+    closure: LLVMValueRef,
+    params_t: &[LLVMTypeRef],
+    return_t: LLVMTypeRef,
+    args: &mut [LLVMValueRef],
+    name: &str,
+) -> Result<LLVMValueRef, String> {
+    convert_args(codegen_context, params_t, args, name)?;
+    unsafe {
+        let code = LLVMBuildExtractValue(codegen_context.ir_builder, closure, 0, c"code".as_ptr());
+        let env = LLVMBuildExtractValue(codegen_context.ir_builder, closure, 1, c"env".as_ptr());
+        let code_params_t = closure_code_params(codegen_context, params_t);
+        let mut code_args = vec![env];
+        code_args.extend_from_slice(args);
+        let name = (name.to_string() + "\0").into_bytes();
+        Ok(build_lowered_indirect_call(
+            codegen_context,
+            code,
+            &code_params_t,
+            return_t,
+            &code_args,
+            std::ffi::CStr::from_bytes_with_nul(&name).unwrap(),
+        ))
+    }
+}
+
+// converts the arguments of a call to the types of the parameters
+pub fn convert_args(
+    codegen_context: &mut CodeGenContext,
+    params_t: &[LLVMTypeRef],
+    args: &mut [LLVMValueRef],
+    name: &str,
+) -> Result<(), String> {
+    unsafe {
+        if params_t.len() != args.len() {
+            return Err(format!(
+                "Function {} expects {} arguments, found {}",
+                name,
+                params_t.len(),
+                args.len()
+            ));
+        }
+        for (i, (arg, param_t)) in args.iter_mut().zip(params_t.iter()).enumerate() {
+            match build_conversion(codegen_context, *arg, *param_t) {
+                Some(value) => *arg = value,
+                None => {
+                    return Err(format!(
+                        "Argument {} of {} must be a {}, found {}",
+                        i + 1,
+                        name,
+                        type_name(*param_t),
+                        type_name(LLVMTypeOf(*arg))
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// struct value of the given type built from its field values
+pub fn build_struct(
+    codegen_context: &mut CodeGenContext,
+    struct_t: LLVMTypeRef,
+    fields: &[LLVMValueRef],
+    name: &str,
+) -> Result<LLVMValueRef, String> {
+    unsafe {
+        let field_count = LLVMCountStructElementTypes(struct_t) as usize;
+        if field_count != fields.len() {
+            return Err(format!(
+                "Struct {} has {} fields, found {}",
+                name,
+                field_count,
+                fields.len()
+            ));
+        }
+
+        log_verbose(format!("Generate struct value {:?}", name));
+        let mut value = LLVMGetUndef(struct_t);
+        for (i, field) in fields.iter().enumerate() {
+            let field_t = LLVMStructGetTypeAtIndex(struct_t, i as u32);
+            let Some(field) = build_conversion(codegen_context, *field, field_t) else {
+                return Err(format!(
+                    "Field {} of {} must be a {}, found {}",
+                    i + 1,
+                    name,
+                    type_name(field_t),
+                    type_name(LLVMTypeOf(*field))
+                ));
+            };
+            value = LLVMBuildInsertValue(
+                codegen_context.ir_builder,
+                value,
+                field,
+                i as u32,
+                c"struct".as_ptr(),
+            );
+        }
+        Ok(value)
+    }
+}
+
+pub fn generate_code(codegen_context: &mut CodeGenContext, function: &dyn Function) {
     log_verbose("===Start generate code===".to_string());
     match function.generate_code(codegen_context) {
         Ok(_) => {}
@@ -52,6 +589,28 @@ pub fn generate_code(codegen_context: &mut CodeGenContext, function: Box<dyn Fun
     log_verbose("===End generate code===".to_string());
 }
 
+pub fn run_passes(codegen_context: &mut CodeGenContext) {
+    unsafe {
+        log_verbose("Run optimization passes".to_string());
+        let options = LLVMCreatePassBuilderOptions();
+        let error = LLVMRunPasses(
+            codegen_context.module,
+            c"function(mem2reg),globaldce".as_ptr(),
+            std::ptr::null_mut(),
+            options,
+        );
+        if !error.is_null() {
+            let message = llvm_sys::error::LLVMGetErrorMessage(error);
+            println!(
+                "Failed to run passes: {}",
+                std::ffi::CStr::from_ptr(message).to_string_lossy()
+            );
+            llvm_sys::error::LLVMDisposeErrorMessage(message);
+        }
+        LLVMDisposePassBuilderOptions(options);
+    }
+}
+
 pub fn dump(codegen_context: &mut CodeGenContext) {
     println!();
     println!();
@@ -92,7 +651,7 @@ pub fn init_pass_module_and_managers() {
     }
 }
 
-pub fn emit_to_file(codegen_context: &mut CodeGenContext) {
+pub fn emit_to_file(codegen_context: &mut CodeGenContext, filename: &str) {
     unsafe {
         LLVM_InitializeAllTargetInfos();
         LLVM_InitializeAllTargets();
@@ -130,7 +689,7 @@ pub fn emit_to_file(codegen_context: &mut CodeGenContext) {
             LLVMGetDataLayoutStr(codegen_context.module),
         );
 
-        let filename = c"output.o".as_ptr();
+        let filename = (filename.to_string() + "\0").into_bytes();
         //let pm = LLVMCreatePassManager();
         //LLVMRunPassManager(pm, codegen_context.module);
         let file_type = LLVMCodeGenFileType::LLVMObjectFile;
@@ -139,7 +698,7 @@ pub fn emit_to_file(codegen_context: &mut CodeGenContext) {
         LLVMTargetMachineEmitToFile(
             target_machine,
             codegen_context.module,
-            filename,
+            filename.as_ptr() as *const i8,
             file_type,
             &mut error_msg,
         );
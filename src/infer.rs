@@ -0,0 +1,420 @@
+use crate::{lexer::Position, types::*};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// Type inference runs on every top-level item before code is generated for it. Parameters,
+// return values and local variables without a type annotation start as type variables, which
+// are unified with the types the body uses them as. Numbers of different types are still
+// converted implicitly like in C, so two known number types never conflict, only unknown types
+// are bound. Only the type of a number literal adapts to a known number it is combined with,
+// other unknown values may still turn out to be doubles. Type variables that no usage determines
+// default to double.
+
+// type of an expression during inference
+#[derive(Debug, PartialEq, Clone)]
+pub enum Term {
+    // unknown type, the index of its binding in the inference
+    Var(usize),
+    Double,
+    Int,
+    Bool,
+    String,
+    Array,
+    Struct(String),
+    Function(Vec<Term>, Box<Term>),
+    Tuple(Vec<Term>),
+}
+
+impl Term {
+    pub fn from_type(ty: &Type) -> Term {
+        match ty {
+            Type::Double => Term::Double,
+            Type::Int => Term::Int,
+            Type::Bool => Term::Bool,
+            Type::Array => Term::Array,
+            Type::String => Term::String,
+            Type::Struct(name) => Term::Struct(name.clone()),
+            Type::Function(params, ret) => Term::Function(
+                params.iter().map(Term::from_type).collect(),
+                Box::new(Term::from_type(ret)),
+            ),
+            Type::Tuple(elements) => Term::Tuple(elements.iter().map(Term::from_type).collect()),
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, Term::Double | Term::Int | Term::Bool)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Term::Var(_) => String::from("unknown type"),
+            Term::Double => String::from("double"),
+            Term::Int => String::from("int"),
+            Term::Bool => String::from("bool"),
+            Term::String => String::from("string"),
+            Term::Array => String::from("array"),
+            Term::Struct(name) => name.clone(),
+            Term::Function(params, ret) => format!(
+                "({}) -> {}",
+                params
+                    .iter()
+                    .map(|param| param.name())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                ret.name()
+            ),
+            Term::Tuple(elements) => format!(
+                "({})",
+                elements
+                    .iter()
+                    .map(|element| element.name())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+// inferred type of a local variable, set once the function declaring it is inferred
+pub type TypeSlot = Rc<RefCell<Option<Type>>>;
+
+// types of the top-level items inferred so far
+#[derive(Default)]
+pub struct TypeEnv {
+    // parameter and return types of functions and externs
+    pub functions: HashMap<String, (Vec<Type>, Type)>,
+    // externs taking variable arguments after their parameters
+    pub variadic: HashSet<String>,
+    pub globals: HashMap<String, Term>,
+    pub structs: HashMap<String, Vec<(String, Type)>>,
+    // annotated parameter and return types of the definitions of the file, which can be used
+    // before their types are inferred
+    pub declared: HashMap<String, (Vec<Option<Type>>, Option<Type>)>,
+    // globals of the file that are not inferred yet
+    pub declared_globals: HashSet<String>,
+}
+
+pub fn type_error(pos: Position, message: String) -> String {
+    format!("Type error at {}: {}", pos, message)
+}
+
+// inference of a single top-level item
+pub struct Inference<'a> {
+    pub env: &'a TypeEnv,
+    // types of the parameters and local variables in scope
+    pub locals: HashMap<String, Term>,
+    // parameter and return types of the functions being inferred together, for recursive calls
+    pub current: HashMap<String, (Vec<Term>, Term)>,
+    // declared definitions and globals the item uses before their types are inferred
+    pub references: HashSet<String>,
+    // locals outside of each enclosing lambda and the names the lambda captures from them
+    lambda_scopes: Vec<(HashMap<String, Term>, Vec<String>)>,
+    bindings: Vec<Option<Term>>,
+    // type variables that can only be bound to numbers
+    numeric: Vec<bool>,
+    // type variables of number literals, which become ints when combined with an int
+    literal: Vec<bool>,
+    slots: Vec<(Term, TypeSlot)>,
+}
+
+impl<'a> Inference<'a> {
+    pub fn new(env: &'a TypeEnv) -> Inference<'a> {
+        Inference {
+            env,
+            locals: HashMap::new(),
+            current: HashMap::new(),
+            references: HashSet::new(),
+            lambda_scopes: vec![],
+            bindings: vec![],
+            numeric: vec![],
+            literal: vec![],
+            slots: vec![],
+        }
+    }
+
+    // type of an annotation, unknown if there is none
+    pub fn annotated(&mut self, ty: &Option<Type>) -> Term {
+        match ty {
+            Some(ty) => Term::from_type(ty),
+            None => self.fresh(),
+        }
+    }
+
+    pub fn fresh(&mut self) -> Term {
+        self.bindings.push(None);
+        self.numeric.push(false);
+        self.literal.push(false);
+        Term::Var(self.bindings.len() - 1)
+    }
+
+    // type of a number literal that is an int or a double depending on its usage
+    pub fn fresh_number(&mut self) -> Term {
+        let term = self.fresh();
+        if let Term::Var(var) = term {
+            self.numeric[var] = true;
+            self.literal[var] = true;
+        }
+        term
+    }
+
+    // a variable initialized with a number literal is no longer adapted to ints
+    pub fn declare_variable(&mut self, term: &Term) {
+        if let Term::Var(var) = self.resolve(term) {
+            self.literal[var] = false;
+        }
+    }
+
+    pub fn resolve(&self, term: &Term) -> Term {
+        match term {
+            Term::Var(var) => match &self.bindings[*var] {
+                Some(bound) => self.resolve(bound),
+                None => term.clone(),
+            },
+            _ => term.clone(),
+        }
+    }
+
+    // whether the type variable is part of the term, binding it would make the type infinite
+    fn occurs(&self, var: usize, term: &Term) -> bool {
+        match self.resolve(term) {
+            Term::Var(other) => var == other,
+            Term::Function(params, ret) => {
+                params.iter().any(|param| self.occurs(var, param)) || self.occurs(var, &ret)
+            }
+            Term::Tuple(elements) => elements.iter().any(|element| self.occurs(var, element)),
+            _ => false,
+        }
+    }
+
+    fn bind(&mut self, var: usize, term: &Term, pos: Position) -> Result<(), String> {
+        if self.occurs(var, term) {
+            return Err(type_error(
+                pos,
+                String::from("a function cannot take or return itself"),
+            ));
+        }
+        if let Term::Var(other) = term {
+            self.numeric[*other] |= self.numeric[var];
+            self.literal[*other] &= self.literal[var];
+        } else if self.numeric[var] && !term.is_number() {
+            return Err(type_error(
+                pos,
+                format!("expected a number, found {}", term.name()),
+            ));
+        }
+        self.bindings[var] = Some(term.clone());
+        Ok(())
+    }
+
+    pub fn unify(&mut self, expected: &Term, actual: &Term, pos: Position) -> Result<(), String> {
+        let (expected, actual) = (self.resolve(expected), self.resolve(actual));
+        match (&expected, &actual) {
+            _ if expected == actual => Ok(()),
+            (Term::Var(var), _) => self.bind(*var, &actual, pos),
+            (_, Term::Var(var)) => self.bind(*var, &expected, pos),
+            (Term::Function(expected_params, expected_ret), Term::Function(params, ret))
+                if expected_params.len() == params.len() =>
+            {
+                // function values are only compatible if all types match exactly
+                for (expected_param, param) in expected_params.iter().zip(params.iter()) {
+                    self.unify(expected_param, param, pos)?;
+                }
+                self.unify(expected_ret, ret, pos)
+            }
+            (Term::Tuple(expected_elements), Term::Tuple(elements))
+                if expected_elements.len() == elements.len() =>
+            {
+                // like function values the element types must match exactly
+                for (expected_element, element) in expected_elements.iter().zip(elements.iter()) {
+                    self.unify(expected_element, element, pos)?;
+                }
+                Ok(())
+            }
+            _ => Err(type_error(
+                pos,
+                format!("expected {}, found {}", expected.name(), actual.name()),
+            )),
+        }
+    }
+
+    // a value of the actual type is used where the expected type is needed, known numbers are
+    // converted implicitly
+    pub fn coerce(&mut self, expected: &Term, actual: &Term, pos: Position) -> Result<(), String> {
+        if self.resolve(expected).is_number() && self.resolve(actual).is_number() {
+            return Ok(());
+        }
+        self.unify(expected, actual, pos)
+    }
+
+    pub fn expect_number(&mut self, term: &Term, pos: Position) -> Result<(), String> {
+        match self.resolve(term) {
+            Term::Var(var) => {
+                self.numeric[var] = true;
+                Ok(())
+            }
+            term if term.is_number() => Ok(()),
+            term => Err(type_error(
+                pos,
+                format!("expected a number, found {}", term.name()),
+            )),
+        }
+    }
+
+    // operand of the bitwise operators, bools are converted to ints
+    pub fn expect_int(&mut self, term: &Term, pos: Position) -> Result<(), String> {
+        match self.resolve(term) {
+            Term::Bool => Ok(()),
+            term => self.unify(&Term::Int, &term, pos),
+        }
+    }
+
+    // type two values are converted to when they are combined by an operator or are the
+    // branches of an if, like common_number_type of the code generation
+    pub fn join(&mut self, lhs: &Term, rhs: &Term, pos: Position) -> Result<Term, String> {
+        let (lhs, rhs) = (self.resolve(lhs), self.resolve(rhs));
+        match (&lhs, &rhs) {
+            _ if lhs.is_number() && rhs.is_number() && lhs != rhs => {
+                if lhs == Term::Double || rhs == Term::Double {
+                    Ok(Term::Double)
+                } else {
+                    Ok(Term::Int)
+                }
+            }
+            (Term::Var(var), number) | (number, Term::Var(var))
+                if number.is_number() && !self.literal[*var] =>
+            {
+                // the unknown number is converted to a double or is the type of the result
+                self.numeric[*var] = true;
+                if *number == Term::Double {
+                    Ok(Term::Double)
+                } else {
+                    Ok(Term::Var(*var))
+                }
+            }
+            _ => {
+                self.unify(&lhs, &rhs, pos)?;
+                Ok(self.resolve(&lhs))
+            }
+        }
+    }
+
+    // type of a local variable in scope, a local of an enclosing function is captured by every
+    // lambda in between
+    pub fn lookup_local(&mut self, name: &str) -> Option<Term> {
+        if let Some(term) = self.locals.get(name) {
+            return Some(term.clone());
+        }
+        for i in (0..self.lambda_scopes.len()).rev() {
+            if let Some(term) = self.lambda_scopes[i].0.get(name).cloned() {
+                for (_, captures) in self.lambda_scopes[i..].iter_mut() {
+                    if !captures.iter().any(|capture| capture == name) {
+                        captures.push(name.to_string());
+                    }
+                }
+                return Some(term);
+            }
+        }
+        None
+    }
+
+    // the body of a lambda only has its parameters as locals, the locals outside are captured
+    pub fn enter_lambda(&mut self) {
+        let outer = std::mem::take(&mut self.locals);
+        self.lambda_scopes.push((outer, vec![]));
+    }
+
+    // restores the locals outside the lambda and returns the names it captured
+    pub fn leave_lambda(&mut self) -> Vec<String> {
+        let (outer, captures) = self.lambda_scopes.pop().unwrap();
+        self.locals = outer;
+        captures
+    }
+
+    // parameter and return types of a function that can be called from the item
+    pub fn signature(&mut self, name: &str) -> Option<(Vec<Term>, Term)> {
+        if let Some((params, ret)) = self.current.get(name) {
+            return Some((params.clone(), ret.clone()));
+        }
+        if let Some((params, ret)) = self.env.functions.get(name) {
+            return Some((
+                params.iter().map(Term::from_type).collect(),
+                Term::from_type(ret),
+            ));
+        }
+        // only the annotated types of a definition that is not inferred yet are known
+        let env = self.env;
+        let (params, ret) = env.declared.get(name)?;
+        self.references.insert(name.to_string());
+        let params = params.iter().map(|param| self.annotated(param)).collect();
+        Some((params, self.annotated(ret)))
+    }
+
+    // type of a global, a global that is not inferred yet has an unknown type
+    pub fn global(&mut self, name: &str) -> Option<Term> {
+        if let Some(term) = self.env.globals.get(name) {
+            return Some(term.clone());
+        }
+        if !self.env.declared_globals.contains(name) {
+            return None;
+        }
+        self.references.insert(name.to_string());
+        Some(self.fresh())
+    }
+
+    // the slot receives the type of the term once the item is inferred
+    pub fn defer(&mut self, term: Term, slot: TypeSlot) {
+        self.slots.push((term, slot));
+    }
+
+    // final type of a term, unknown types are doubles
+    pub fn to_type(&self, term: &Term) -> Type {
+        match self.to_final_term(term) {
+            Term::Var(_) | Term::Double => Type::Double,
+            Term::Int => Type::Int,
+            Term::Bool => Type::Bool,
+            Term::String => Type::String,
+            Term::Array => Type::Array,
+            Term::Struct(name) => Type::Struct(name),
+            Term::Function(params, ret) => Type::Function(
+                params.iter().map(|param| self.to_type(param)).collect(),
+                Box::new(self.to_type(&ret)),
+            ),
+            Term::Tuple(elements) => Type::Tuple(
+                elements
+                    .iter()
+                    .map(|element| self.to_type(element))
+                    .collect(),
+            ),
+        }
+    }
+
+    // term without type variables, unknown types are doubles
+    pub fn to_final_term(&self, term: &Term) -> Term {
+        match self.resolve(term) {
+            Term::Var(_) => Term::Double,
+            Term::Function(params, ret) => Term::Function(
+                params
+                    .iter()
+                    .map(|param| self.to_final_term(param))
+                    .collect(),
+                Box::new(self.to_final_term(&ret)),
+            ),
+            Term::Tuple(elements) => Term::Tuple(
+                elements
+                    .iter()
+                    .map(|element| self.to_final_term(element))
+                    .collect(),
+            ),
+            term => term,
+        }
+    }
+
+    // fills the slots of the local variables with their inferred types
+    pub fn finish(&mut self) {
+        for (term, slot) in self.slots.iter() {
+            *slot.borrow_mut() = Some(self.to_type(term));
+        }
+    }
+}
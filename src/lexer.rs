@@ -3,21 +3,89 @@ pub enum Token {
     Eof,
     Def,
     Extern,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Binary,
+    Unary,
+    Var,
+    While,
+    Do,
+    Break,
+    Continue,
+    Global,
+    Const,
+    Struct,
+    Import,
+    Match,
     Identifier,
     Number,
+    String,
+    Operator,
     Character,
+    // malformed literal, the error message is stored in identifier_str
+    Error,
 }
 
 const IDENT_DEF: &str = "def";
 const IDENT_EXTERN: &str = "extern";
+const IDENT_IF: &str = "if";
+const IDENT_THEN: &str = "then";
+const IDENT_ELSE: &str = "else";
+const IDENT_FOR: &str = "for";
+const IDENT_IN: &str = "in";
+const IDENT_BINARY: &str = "binary";
+const IDENT_UNARY: &str = "unary";
+const IDENT_VAR: &str = "var";
+const IDENT_WHILE: &str = "while";
+const IDENT_DO: &str = "do";
+const IDENT_BREAK: &str = "break";
+const IDENT_CONTINUE: &str = "continue";
+const IDENT_GLOBAL: &str = "global";
+const IDENT_CONST: &str = "const";
+const IDENT_STRUCT: &str = "struct";
+const IDENT_IMPORT: &str = "import";
+const IDENT_MATCH: &str = "match";
+const IDENT_INF: &str = "inf";
+const IDENT_NAN: &str = "nan";
 const IDENT_CHAR_COMMENT: char = '#';
+const IDENT_CHAR_QUOTE: char = '"';
+const OPERATOR_CHARS: &str = "+-*/%<>=!&|^~:?@$";
+const MULTI_CHAR_OPERATORS: [&str; 10] =
+    ["<=", ">=", "==", "!=", "&&", "||", "<<", ">>", "->", "=>"];
+
+// position of a token in the source, lines and columns are counted from 1
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
 
 pub struct Lexer {
     pub identifier_str: String,
     pub num_val: f64,
+    // unescaped content of a string literal, kept apart from identifier_str so that a string
+    // is never taken for punctuation
+    pub string_val: String,
     pub last_char: Option<char>,
     pub buffer: Vec<char>,
     pub char_idx: usize,
+    // line of the next character to be read, counted over all buffers
+    pub line: u32,
+    // index of the first character of the current line in the buffer
+    line_start: usize,
+    // position the last token started at
+    pub token_pos: Position,
+    // kind of the last malformed literal, which the parser reads in its place
+    pub malformed_token: Token,
 }
 
 impl Lexer {
@@ -25,21 +93,31 @@ impl Lexer {
         Lexer {
             identifier_str: String::new(),
             num_val: 0.0,
+            string_val: String::new(),
             last_char: Some(' '),
             buffer: vec![],
             char_idx: 0,
+            line: 1,
+            line_start: 0,
+            token_pos: Position { line: 1, column: 1 },
+            malformed_token: Token::Number,
         }
     }
 
     pub fn set_buffer(&mut self, buffer: String) {
         self.buffer = buffer.chars().collect();
         self.char_idx = 0;
+        self.line_start = 0;
         self.last_char = Some(' ');
     }
 
     fn get_next_char(&mut self) -> Option<char> {
         let result = self.buffer.get(self.char_idx);
         self.char_idx += 1;
+        if result == Some(&'\n') {
+            self.line += 1;
+            self.line_start = self.char_idx;
+        }
         match result {
             Some(c) => Some(*c),
             None => None,
@@ -49,6 +127,7 @@ impl Lexer {
     pub fn get_token(&mut self) -> Token {
         self.identifier_str = String::new();
         self.num_val = 0.0;
+        self.string_val = String::new();
 
         while let Some(c) = self.last_char {
             if !c.is_whitespace() {
@@ -57,13 +136,18 @@ impl Lexer {
             self.last_char = self.get_next_char();
         }
 
+        // the current character was read last, its index is one before char_idx
+        self.token_pos = Position {
+            line: self.line,
+            column: (self.char_idx - self.line_start) as u32,
+        };
         if self.last_char.is_none() {
             return Token::Eof;
         }
 
         if self.last_char.unwrap().is_ascii_alphabetic() {
             self.get_identifier()
-        } else if self.last_char.unwrap().is_numeric() {
+        } else if self.last_char.unwrap().is_ascii_digit() || self.is_leading_dot_number() {
             self.get_number()
         } else if self.last_char.unwrap() == IDENT_CHAR_COMMENT {
             loop {
@@ -76,6 +160,10 @@ impl Lexer {
                 }
             }
             self.get_token()
+        } else if self.last_char.unwrap() == IDENT_CHAR_QUOTE {
+            self.get_string()
+        } else if OPERATOR_CHARS.contains(self.last_char.unwrap()) {
+            self.get_operator()
         } else {
             self.identifier_str.push(self.last_char.unwrap());
             self.last_char = self.get_next_char();
@@ -94,27 +182,187 @@ impl Lexer {
         match self.identifier_str.as_str() {
             IDENT_DEF => Token::Def,
             IDENT_EXTERN => Token::Extern,
+            IDENT_IF => Token::If,
+            IDENT_THEN => Token::Then,
+            IDENT_ELSE => Token::Else,
+            IDENT_FOR => Token::For,
+            IDENT_IN => Token::In,
+            IDENT_BINARY => Token::Binary,
+            IDENT_UNARY => Token::Unary,
+            IDENT_VAR => Token::Var,
+            IDENT_WHILE => Token::While,
+            IDENT_DO => Token::Do,
+            IDENT_BREAK => Token::Break,
+            IDENT_CONTINUE => Token::Continue,
+            IDENT_GLOBAL => Token::Global,
+            IDENT_CONST => Token::Const,
+            IDENT_STRUCT => Token::Struct,
+            IDENT_IMPORT => Token::Import,
+            IDENT_MATCH => Token::Match,
+            IDENT_INF | IDENT_NAN => {
+                self.num_val = if self.identifier_str == IDENT_INF {
+                    f64::INFINITY
+                } else {
+                    f64::NAN
+                };
+                self.identifier_str = String::new();
+                Token::Number
+            }
             _ => Token::Identifier,
         }
     }
 
+    // the unescaped content of the string literal is stored in string_val
+    fn get_string(&mut self) -> Token {
+        let mut error = None;
+        loop {
+            self.last_char = self.get_next_char();
+            let c = match self.last_char {
+                Some(IDENT_CHAR_QUOTE) => break,
+                Some('\\') => match self.get_next_char() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    Some(IDENT_CHAR_QUOTE) => IDENT_CHAR_QUOTE,
+                    // the rest of the literal is read so that lexing goes on behind it
+                    Some(c) => {
+                        error.get_or_insert(format!(
+                            "Unknown escape sequence \\{c} in string literal"
+                        ));
+                        c
+                    }
+                    None => {
+                        self.last_char = None;
+                        return self.malformed(Token::String, "Unterminated string literal");
+                    }
+                },
+                Some(c) => c,
+                None => return self.malformed(Token::String, "Unterminated string literal"),
+            };
+            self.string_val.push(c);
+        }
+        self.last_char = self.get_next_char(); // eat closing quote
+        match error {
+            Some(message) => self.malformed(Token::String, &message),
+            None => Token::String,
+        }
+    }
+
+    fn get_operator(&mut self) -> Token {
+        self.identifier_str.push(self.last_char.unwrap());
+        self.last_char = self.get_next_char();
+        if let Some(c) = self.last_char {
+            let op = format!("{}{}", self.identifier_str, c);
+            if MULTI_CHAR_OPERATORS.contains(&op.as_str()) {
+                self.identifier_str = op;
+                self.last_char = self.get_next_char();
+            }
+        }
+        Token::Operator
+    }
+
+    // character before the current one in the buffer
+    fn previous_char(&self) -> Option<char> {
+        self.char_idx
+            .checked_sub(2)
+            .and_then(|idx| self.buffer.get(idx))
+            .copied()
+    }
+
+    // '.5' is a number, but the '.' of 'p.x', 't.0' or 'f(x).0' accesses a field or element
+    fn is_leading_dot_number(&self) -> bool {
+        let followed_by_digit = self
+            .buffer
+            .get(self.char_idx)
+            .is_some_and(|c| c.is_ascii_digit());
+        let after_value = self
+            .previous_char()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == ')' || c == ']');
+        self.last_char == Some('.') && followed_by_digit && !after_value
+    }
+
+    // number ::= decimal | '0x' hexdigits | '0b' bindigits | 'inf' | 'nan'
+    // decimal ::= digits ('.' digits?)? exponent? | '.' digits exponent?
+    // digits may be separated by '_', the whole literal is read so that a malformed one like
+    // '1.2.3' is reported as a single error
     fn get_number(&mut self) -> Token {
+        // the element index of 't.0.1' ends at the next '.'
+        let is_element_index = self.previous_char() == Some('.');
         let mut num_str = String::new();
         loop {
             num_str.push(self.last_char.unwrap());
             self.last_char = self.get_next_char();
-            if self.last_char.is_none()
-                || (!self.last_char.unwrap().is_numeric() && self.last_char.unwrap() != '.')
-            {
+            let is_radix = num_str.starts_with("0x") || num_str.starts_with("0b");
+            let continues = match self.last_char {
+                Some(c) if c.is_ascii_alphanumeric() || c == '_' => true,
+                Some('.') => !is_element_index,
+                Some('+' | '-') => !is_radix && !is_element_index && num_str.ends_with(['e', 'E']),
+                _ => false,
+            };
+            if !continues {
                 break;
             }
         }
-        self.num_val = match num_str.parse::<f64>() {
-            Ok(val) => val,
-            Err(error) => panic!("Failed to parse number token {num_str:?}: {error:?}"),
-        };
-        Token::Number
+        match parse_number(&num_str) {
+            Some(val) => {
+                self.num_val = val;
+                Token::Number
+            }
+            None => self.malformed(
+                Token::Number,
+                &format!("Malformed number literal {:?}", num_str),
+            ),
+        }
     }
+
+    // reports a malformed literal of the given kind with the position it starts at
+    fn malformed(&mut self, kind: Token, message: &str) -> Token {
+        self.identifier_str = lexer_error(self.token_pos, message.to_string());
+        self.malformed_token = kind;
+        Token::Error
+    }
+}
+
+pub fn lexer_error(pos: Position, message: String) -> String {
+    format!("Lexer error at {}: {}", pos, message)
+}
+
+// the value of a number literal, None if it is malformed
+fn parse_number(num_str: &str) -> Option<f64> {
+    let (digits, radix) = match num_str.get(..2) {
+        Some("0x") => (&num_str[2..], 16),
+        Some("0b") => (&num_str[2..], 2),
+        _ => (num_str, 10),
+    };
+    // separators have to be between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' {
+            let is_digit = |idx: Option<usize>| {
+                idx.and_then(|idx| chars.get(idx))
+                    .is_some_and(|c| c.is_digit(radix))
+            };
+            if !is_digit(i.checked_sub(1)) || !is_digit(Some(i + 1)) {
+                return None;
+            }
+        }
+    }
+    let digits = digits.replace('_', "");
+    if radix != 10 {
+        return u64::from_str_radix(&digits, radix)
+            .ok()
+            .map(|val| val as f64);
+    }
+    // only digits, '.' and the exponent are allowed, parse would also accept 'inf' and 'nan'
+    if !digits
+        .chars()
+        .all(|c| c.is_ascii_digit() || ".eE+-".contains(c))
+    {
+        return None;
+    }
+    digits.parse::<f64>().ok()
 }
 
 #[cfg(test)]
@@ -132,7 +380,7 @@ mod tests {
         assert_eq!(lexer.get_token(), Token::Character);
         assert_eq!(lexer.identifier_str, "ä");
 
-        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.get_token(), Token::Operator);
         assert_eq!(lexer.identifier_str, "+");
 
         assert_eq!(lexer.get_token(), Token::Identifier);
@@ -149,4 +397,191 @@ mod tests {
 
         assert_eq!(lexer.get_token(), Token::Eof);
     }
+
+    #[test]
+    fn test_get_token_number() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "1e-9 2.5E3 0xff_FF 0b1010 1_000_000 .5 x*.25 5. inf nan",
+        ));
+        for val in [1e-9, 2.5e3, 65535.0, 10.0, 1e6, 0.5] {
+            assert_eq!(lexer.get_token(), Token::Number);
+            assert_eq!(lexer.num_val, val);
+        }
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Operator);
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.num_val, 0.25);
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.num_val, 5.0);
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.num_val, f64::INFINITY);
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert!(lexer.num_val.is_nan());
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_malformed_number() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("1 + 1.2.3\n 0x 1__0 2_ 1e 0b12 3abc"));
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.get_token(), Token::Operator);
+        assert_eq!(lexer.get_token(), Token::Error);
+        assert_eq!(
+            lexer.identifier_str,
+            "Lexer error at line 1, column 5: Malformed number literal \"1.2.3\""
+        );
+        for _ in 0..6 {
+            assert_eq!(lexer.get_token(), Token::Error);
+        }
+        assert_eq!(
+            lexer.token_pos,
+            Position {
+                line: 2,
+                column: 21
+            }
+        );
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_field_access() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("struct p.x"));
+        assert_eq!(lexer.get_token(), Token::Struct);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.identifier_str, "p");
+        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.identifier_str, ".");
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.identifier_str, "x");
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_pos() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("a # b\n\n  c\n"));
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.token_pos, Position { line: 1, column: 1 });
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.token_pos, Position { line: 3, column: 3 });
+        assert_eq!(lexer.get_token(), Token::Eof);
+
+        // lines are counted across buffers
+        lexer.set_buffer(String::from("d"));
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.token_pos, Position { line: 4, column: 1 });
+    }
+
+    #[test]
+    fn test_get_token_if_then_else() {
+        let input = String::from("if x then 1 else iffy");
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(input);
+        assert_eq!(lexer.get_token(), Token::If);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Then);
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.get_token(), Token::Else);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.identifier_str, "iffy");
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_for_in() {
+        let input = String::from("for i = 1, i in foo");
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(input);
+        assert_eq!(lexer.get_token(), Token::For);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Operator);
+        assert_eq!(lexer.identifier_str, "=");
+        assert_eq!(lexer.get_token(), Token::Number);
+        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::In);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_operators() {
+        let input = String::from("a<=b<c==!d!=-e%(f)&&g||h<<i>>j->k=>l");
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(input);
+        let expected = [
+            (Token::Identifier, "a"),
+            (Token::Operator, "<="),
+            (Token::Identifier, "b"),
+            (Token::Operator, "<"),
+            (Token::Identifier, "c"),
+            (Token::Operator, "=="),
+            (Token::Operator, "!"),
+            (Token::Identifier, "d"),
+            (Token::Operator, "!="),
+            (Token::Operator, "-"),
+            (Token::Identifier, "e"),
+            (Token::Operator, "%"),
+            (Token::Character, "("),
+            (Token::Identifier, "f"),
+            (Token::Character, ")"),
+            (Token::Operator, "&&"),
+            (Token::Identifier, "g"),
+            (Token::Operator, "||"),
+            (Token::Identifier, "h"),
+            (Token::Operator, "<<"),
+            (Token::Identifier, "i"),
+            (Token::Operator, ">>"),
+            (Token::Identifier, "j"),
+            (Token::Operator, "->"),
+            (Token::Identifier, "k"),
+            (Token::Operator, "=>"),
+            (Token::Identifier, "l"),
+        ];
+        for (token, identifier) in expected {
+            assert_eq!(lexer.get_token(), token);
+            assert_eq!(lexer.identifier_str, identifier);
+        }
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_string() {
+        let input = String::from(r#"print("a \"b\"\tc\\n\n", "") "#);
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(input);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.get_token(), Token::String);
+        assert_eq!(lexer.string_val, "a \"b\"\tc\\n\n");
+        assert_eq!(lexer.identifier_str, "");
+        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.get_token(), Token::String);
+        assert_eq!(lexer.string_val, "");
+        assert_eq!(lexer.get_token(), Token::Character);
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_get_token_malformed_string() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("a \"b\\qc\" d \"abc"));
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Error);
+        assert_eq!(
+            lexer.identifier_str,
+            "Lexer error at line 1, column 3: Unknown escape sequence \\q in string literal"
+        );
+        assert_eq!(lexer.malformed_token, Token::String);
+        assert_eq!(lexer.get_token(), Token::Identifier);
+        assert_eq!(lexer.get_token(), Token::Error);
+        assert_eq!(
+            lexer.identifier_str,
+            "Lexer error at line 1, column 12: Unterminated string literal"
+        );
+        assert_eq!(lexer.get_token(), Token::Eof);
+    }
 }
@@ -1,13 +1,28 @@
+mod abi;
 mod ast;
+mod closure;
 mod codegen;
+mod infer;
 mod lexer;
 mod logger;
+mod module;
 mod parser;
+mod runtime;
+mod types;
+use crate::codegen::CodeGenOptions;
 use crate::lexer::Lexer;
 use crate::parser::Parser;
 
 fn main() {
+    let mut options = CodeGenOptions::default();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--strip-asserts" => options.strip_asserts = true,
+            _ => panic!("Unknown argument {}", arg),
+        }
+    }
+
     let mut lexer = Lexer::new();
     let mut parser = Parser::new(&mut lexer);
-    parser.main_loop();
+    parser.main_loop(options);
 }
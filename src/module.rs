@@ -0,0 +1,196 @@
+extern crate llvm_sys as llvm;
+use crate::{codegen::*, infer::*, parser::*, types::*};
+use llvm::LLVMLinkage;
+use llvm::core::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// An imported file is a module that is compiled into its own object file, named after the
+// module next to output.o. Its functions are exported under names qualified by the module
+// name, the file name without extension, and the importing module declares them as externs.
+// Globals, constants, operators and top-level expressions stay private to the module.
+
+// items of a compiled module that an importing module can use
+#[derive(Clone)]
+pub struct ModuleExports {
+    pub name: String,
+    // qualified names with parameter and return types
+    pub functions: Vec<(String, Vec<Type>, Type)>,
+    // structs the exported functions can use with qualified names and field types
+    pub structs: Vec<(String, Vec<(String, Type)>)>,
+}
+
+// modules compiled so far and the chain of imports currently being compiled
+#[derive(Default)]
+pub struct Modules {
+    compiled: HashMap<PathBuf, ModuleExports>,
+    importing: Vec<PathBuf>,
+    // files of the module names, which name the object files and qualify the symbols
+    names: HashMap<String, PathBuf>,
+}
+
+impl Modules {
+    // compiles the module of the file unless it was compiled before
+    pub fn import(
+        &mut self,
+        path: &Path,
+        options: &CodeGenOptions,
+    ) -> Result<ModuleExports, String> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("Cannot import {}: {}", path.display(), e))?;
+        if let Some(exports) = self.compiled.get(&path) {
+            return Ok(exports.clone());
+        }
+        if let Some(start) = self.importing.iter().position(|other| *other == path) {
+            let cycle: Vec<String> = self.importing[start..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(format!("Import cycle {}", cycle.join(" -> ")));
+        }
+
+        let name = module_name(&path)?;
+        if name == "output" {
+            return Err(format!(
+                "Cannot import {}: output.o is the object file of the program",
+                path.display()
+            ));
+        }
+        if let Some(other) = self.names.get(&name) {
+            return Err(format!(
+                "Cannot import {}: module {} is imported from {} already",
+                path.display(),
+                name,
+                other.display()
+            ));
+        }
+        self.names.insert(name.clone(), path.clone());
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Cannot import {}: {}", path.display(), e))?;
+        self.importing.push(path.clone());
+        let exports = compile_module(&name, source, &path, self, options.clone());
+        self.importing.pop();
+        self.compiled.insert(path, exports.clone());
+        Ok(exports)
+    }
+}
+
+// the file name without extension, which qualifies the names of the module
+fn module_name(path: &Path) -> Result<String, String> {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        || !chars.all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(format!(
+            "Cannot import {}: the file name is not an identifier",
+            path.display()
+        ));
+    }
+    Ok(name)
+}
+
+// names the module imported itself are qualified already
+fn qualify_name(name: &str, module: &str) -> String {
+    if name.contains('.') {
+        name.to_string()
+    } else {
+        format!("{}.{}", module, name)
+    }
+}
+
+// qualifies the structs declared by the module with its name
+fn qualify(ty: &Type, module: &str) -> Type {
+    match ty {
+        Type::Struct(name) => Type::Struct(qualify_name(name, module)),
+        Type::Function(params, ret) => Type::Function(
+            params.iter().map(|param| qualify(param, module)).collect(),
+            Box::new(qualify(ret, module)),
+        ),
+        Type::Tuple(elements) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| qualify(element, module))
+                .collect(),
+        ),
+        ty => ty.clone(),
+    }
+}
+
+// top-level expressions have no name, operators cannot be called with a qualified name
+fn is_private(function_name: &str) -> bool {
+    let operator = function_name
+        .strip_prefix("binary")
+        .or_else(|| function_name.strip_prefix("unary"));
+    function_name.is_empty()
+        || operator.is_some_and(|op| op.starts_with(|c: char| !c.is_ascii_alphanumeric()))
+}
+
+// renames the functions defined by the module to their qualified names and hides everything
+// else from the other object files
+pub fn export_module(
+    codegen_context: &mut CodeGenContext,
+    type_env: &TypeEnv,
+    name: &str,
+) -> ModuleExports {
+    let mut functions = vec![];
+    unsafe {
+        let mut function = LLVMGetFirstFunction(codegen_context.module);
+        while !function.is_null() {
+            let function_name = value_name(function);
+            if LLVMIsDeclaration(function) == 0
+                && LLVMGetLinkage(function) == LLVMLinkage::LLVMExternalLinkage
+            {
+                match type_env.functions.get(&function_name) {
+                    Some((params, ret)) if !is_private(&function_name) => {
+                        let qualified = qualify_name(&function_name, name);
+                        LLVMSetValueName2(
+                            function,
+                            qualified.as_ptr() as *const i8,
+                            qualified.len(),
+                        );
+                        functions.push((
+                            qualified,
+                            params.iter().map(|param| qualify(param, name)).collect(),
+                            qualify(ret, name),
+                        ));
+                    }
+                    _ => LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage),
+                }
+            }
+            function = LLVMGetNextFunction(function);
+        }
+
+        let mut global = LLVMGetFirstGlobal(codegen_context.module);
+        while !global.is_null() {
+            if LLVMIsDeclaration(global) == 0
+                && LLVMGetLinkage(global) == LLVMLinkage::LLVMExternalLinkage
+            {
+                LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+            }
+            global = LLVMGetNextGlobal(global);
+        }
+    }
+
+    let structs = type_env
+        .structs
+        .iter()
+        .map(|(struct_name, fields)| {
+            let fields = fields
+                .iter()
+                .map(|(field, ty)| (field.clone(), qualify(ty, name)))
+                .collect();
+            (qualify_name(struct_name, name), fields)
+        })
+        .collect();
+    ModuleExports {
+        name: name.to_string(),
+        functions,
+        structs,
+    }
+}
@@ -1,18 +1,63 @@
-use crate::{ast::*, codegen::*, lexer::*, logger::*};
+use crate::{ast::*, codegen::*, infer::*, lexer::*, logger::*, module::*, types::*};
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 
 use std::io::{stdin, stdout};
 
+const DEFAULT_BINARY_OP_PRECEDENCE: i8 = 30;
+const MAX_BINARY_OP_PRECEDENCE: i8 = 100;
+
 pub struct Parser<'a> {
     lexer: &'a mut Lexer,
     cur_token: Option<Token>,
+    op_precedence: HashMap<String, i8>,
+    loop_depth: u32,
+    // declared structs, which can be used as types
+    struct_names: HashSet<String>,
+    // names of the imported modules, which qualify the names of their items
+    module_names: HashSet<String>,
+    // imports are relative to the directory of the file being parsed
+    directory: PathBuf,
+    // reported malformed literals, the items containing them are dropped
+    lexer_errors: Vec<String>,
+    // whether the current token replaces a malformed literal
+    is_malformed: bool,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: &'a mut Lexer) -> Parser<'a> {
+        let op_precedence = HashMap::from([
+            (String::from("="), 2),
+            (String::from("||"), 4),
+            (String::from("&&"), 5),
+            (String::from("|"), 6),
+            (String::from("^"), 6),
+            (String::from("&"), 6),
+            (String::from("=="), 7),
+            (String::from("!="), 7),
+            (String::from("<"), 8),
+            (String::from(">"), 8),
+            (String::from("<="), 8),
+            (String::from(">="), 8),
+            (String::from("<<"), 9),
+            (String::from(">>"), 9),
+            (String::from("+"), 10),
+            (String::from("-"), 10),
+            (String::from("*"), 20),
+            (String::from("/"), 20),
+            (String::from("%"), 20),
+        ]);
         Parser {
             lexer,
             cur_token: None,
+            op_precedence,
+            loop_depth: 0,
+            struct_names: HashSet::new(),
+            module_names: HashSet::new(),
+            directory: PathBuf::from("."),
+            lexer_errors: vec![],
+            is_malformed: false,
         }
     }
 
@@ -24,10 +69,22 @@ impl<'a> Parser<'a> {
         result
     }
 
+    // stringexpr ::= string
+    fn parse_string_expr(&mut self) -> Box<dyn Expr> {
+        let result = StringExprAst::new(self.lexer.string_val.clone());
+        self.read_token(); // eat string
+        log_verbose(String::from("Parsed string expression"));
+        Box::new(result)
+    }
+
     // parenthesisexpr ::= '(' expr ')'
+    // parenthesisexpr ::= tupleexpr
     fn parse_parenthesis_expr(&mut self) -> Box<dyn Expr> {
         self.read_token(); // eat (
         let result = self.parse_expr();
+        if self.lexer.identifier_str == "," {
+            return self.parse_tuple_expr(result);
+        }
         if self.lexer.identifier_str != ")" {
             panic!("Expected ')'");
         }
@@ -36,15 +93,57 @@ impl<'a> Parser<'a> {
         result
     }
 
+    // tupleexpr ::= '(' expr (',' expr)+ ')'
+    fn parse_tuple_expr(&mut self, first: Box<dyn Expr>) -> Box<dyn Expr> {
+        let mut elements = vec![first];
+        while self.lexer.identifier_str == "," {
+            self.read_token(); // eat ,
+            elements.push(self.parse_expr());
+        }
+        if self.lexer.identifier_str != ")" {
+            panic!("Expected ',' or ')' in tuple");
+        }
+        self.read_token(); // eat )
+        log_verbose(String::from("Parsed tuple expression"));
+        Box::new(TupleExprAst::new(elements))
+    }
+
+    // arrayexpr ::= '[' (expr (',' expr)*)? ']'
+    fn parse_array_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat [
+
+        let mut elements: Vec<Box<dyn Expr>> = vec![];
+        if self.lexer.identifier_str != "]" {
+            loop {
+                elements.push(self.parse_expr());
+
+                if self.lexer.identifier_str == "]" {
+                    break;
+                }
+
+                if self.lexer.identifier_str != "," {
+                    panic!("Expected ',' or ']' in array literal");
+                }
+
+                self.read_token(); // eat ,
+            }
+        }
+
+        self.read_token(); // eat ]
+        log_verbose(String::from("Parsed array expression"));
+        Box::new(ArrayExprAst::new(elements, pos))
+    }
+
     // variable references and function calls
-    // identifierexpr ::= identifier
-    // identifierexpr ::= identifier '(' expr* ')'
+    // identifierexpr ::= qualifiedname
+    // identifierexpr ::= qualifiedname '(' expr* ')'
     fn parse_identifier_expr(&mut self) -> Box<dyn Expr> {
-        let identifier = self.lexer.identifier_str.clone();
-        self.read_token(); // eat identifier
+        let pos = self.lexer.token_pos;
+        let identifier = self.parse_qualified_name();
         if self.lexer.identifier_str != "(" {
             log_verbose(String::from("Parsed identifier"));
-            return Box::new(VariableExprAst::new(identifier));
+            return Box::new(VariableExprAst::new(identifier, pos));
         }
 
         // function call
@@ -69,20 +168,349 @@ impl<'a> Parser<'a> {
         }
 
         self.read_token(); // eat )
+        if identifier == "assert" {
+            // assert needs the source line for its failure message
+            let mut args = args.into_iter();
+            let (Some(cond), code, None) = (args.next(), args.next(), args.next()) else {
+                panic!("assert expects a condition and an optional exit code");
+            };
+            log_verbose(String::from("Parsed assert"));
+            return Box::new(AssertExprAst::new(cond, code, pos));
+        }
         log_verbose(String::from("Parsed function call"));
-        Box::new(FunctionCallExprAst::new(identifier, args))
+        Box::new(FunctionCallExprAst::new(identifier, args, pos))
+    }
+
+    // qualifiedname ::= identifier ('.' identifier)?
+    // the name is only qualified if the first identifier names an imported module
+    fn parse_qualified_name(&mut self) -> String {
+        let name = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+        if !self.module_names.contains(&name)
+            || self.cur_token != Some(Token::Character)
+            || self.lexer.identifier_str != "."
+        {
+            return name;
+        }
+        self.read_token(); // eat .
+        if self.cur_token != Some(Token::Identifier) {
+            panic!("Expected a name after '{}.'", name);
+        }
+        let member = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+        format!("{}.{}", name, member)
+    }
+
+    // ifexpr ::= 'if' expr 'then' expr 'else' expr
+    fn parse_if_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat if
+        let cond = self.parse_expr();
+
+        if self.cur_token != Some(Token::Then) {
+            panic!("Expected 'then'");
+        }
+        self.read_token(); // eat then
+        let then = self.parse_expr();
+
+        if self.cur_token != Some(Token::Else) {
+            panic!("Expected 'else'");
+        }
+        self.read_token(); // eat else
+        let otherwise = self.parse_expr();
+
+        log_verbose(String::from("Parsed if expression"));
+        Box::new(IfExprAst::new(cond, then, otherwise, pos))
+    }
+
+    // forexpr ::= 'for' identifier '=' expr ',' expr (',' expr)? 'in' expr
+    fn parse_for_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat for
+        if self.cur_token != Some(Token::Identifier) {
+            panic!("Expected identifier after 'for'");
+        }
+        let var_name = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+
+        if self.lexer.identifier_str != "=" {
+            panic!("Expected '=' after for loop variable");
+        }
+        self.read_token(); // eat =
+        let start = self.parse_expr();
+
+        if self.lexer.identifier_str != "," {
+            panic!("Expected ',' after for loop start value");
+        }
+        self.read_token(); // eat ,
+        let end = self.parse_expr();
+
+        let mut step = None;
+        if self.lexer.identifier_str == "," {
+            self.read_token(); // eat ,
+            step = Some(self.parse_expr());
+        }
+
+        if self.cur_token != Some(Token::In) {
+            panic!("Expected 'in' after for loop header");
+        }
+        self.read_token(); // eat in
+        let body = self.parse_loop_body();
+
+        log_verbose(format!("Parsed for expression {}", var_name));
+        Box::new(ForExprAst::new(var_name, start, end, step, body, pos))
+    }
+
+    // whileexpr ::= 'while' expr 'do' expr
+    fn parse_while_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat while
+        let cond = self.parse_expr();
+
+        if self.cur_token != Some(Token::Do) {
+            panic!("Expected 'do' after while condition");
+        }
+        self.read_token(); // eat do
+        let body = self.parse_loop_body();
+
+        log_verbose(String::from("Parsed while expression"));
+        Box::new(WhileExprAst::new(cond, body, pos))
+    }
+
+    // break and continue are only allowed in the body of a loop
+    fn parse_loop_body(&mut self) -> Box<dyn Expr> {
+        self.loop_depth += 1;
+        let body = self.parse_expr();
+        self.loop_depth -= 1;
+        body
+    }
+
+    // loopcontrolexpr ::= 'break' | 'continue'
+    fn parse_loop_control_expr(&mut self) -> Box<dyn Expr> {
+        let is_break = self.cur_token == Some(Token::Break);
+        if self.loop_depth == 0 {
+            panic!("'{}' outside of a loop", self.lexer.identifier_str);
+        }
+        self.read_token(); // eat break or continue
+        log_verbose(String::from("Parsed loop control expression"));
+        Box::new(LoopControlExprAst::new(is_break))
+    }
+
+    // varexpr ::= 'var' vardeclaration (',' vardeclaration)* 'in' expr
+    fn parse_var_expr(&mut self) -> Box<dyn Expr> {
+        self.read_token(); // eat var
+
+        let mut vars = vec![];
+        loop {
+            if self.lexer.identifier_str == "(" {
+                self.parse_destructuring(&mut vars);
+            } else {
+                self.parse_var_declaration(&mut vars);
+            }
+
+            if self.lexer.identifier_str != "," {
+                break;
+            }
+            self.read_token(); // eat ,
+        }
+
+        if self.cur_token != Some(Token::In) {
+            panic!("Expected 'in' after var declarations");
+        }
+        self.read_token(); // eat in
+        let body = self.parse_expr();
+
+        log_verbose(String::from("Parsed var expression"));
+        Box::new(VarExprAst::new(vars, body))
+    }
+
+    // vardeclaration ::= identifier ('=' expr)?
+    fn parse_var_declaration(&mut self, vars: &mut Vec<(String, Option<Box<dyn Expr>>)>) {
+        if self.cur_token != Some(Token::Identifier) {
+            panic!("Expected identifier after 'var'");
+        }
+        let name = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+
+        let mut init = None;
+        if self.lexer.identifier_str == "=" {
+            self.read_token(); // eat =
+            init = Some(self.parse_expr());
+        }
+        vars.push((name, init));
+    }
+
+    // vardeclaration ::= '(' identifier (',' identifier)* ')' '=' expr
+    // the tuple is stored in a variable whose name is no identifier, the variables are
+    // initialized with its elements
+    fn parse_destructuring(&mut self, vars: &mut Vec<(String, Option<Box<dyn Expr>>)>) {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat (
+        let mut names = vec![];
+        while self.cur_token == Some(Token::Identifier) {
+            names.push(self.lexer.identifier_str.clone());
+            self.read_token(); // eat identifier
+            if self.lexer.identifier_str == "," {
+                self.read_token(); // eat ,
+            } else if self.lexer.identifier_str != ")" {
+                panic!("Expected ',' or ')' in destructuring");
+            }
+        }
+        if self.lexer.identifier_str != ")" || names.is_empty() {
+            panic!("Expected variable names in destructuring");
+        }
+        self.read_token(); // eat )
+        if self.lexer.identifier_str != "=" {
+            panic!("Expected '=' after destructuring");
+        }
+        self.read_token(); // eat =
+        let init = self.parse_expr();
+
+        let tuple_name = format!("({})", names.join(", "));
+        vars.push((tuple_name.clone(), Some(init)));
+        for (i, name) in names.iter().enumerate() {
+            let tuple = Box::new(VariableExprAst::new(tuple_name.clone(), pos));
+            let element = TupleIndexExprAst::new(tuple, i, Some(names.len()), pos);
+            vars.push((name.clone(), Some(Box::new(element))));
+        }
+    }
+
+    // blockexpr ::= '{' (expr (';' expr)* ';'?)? '}'
+    fn parse_block_expr(&mut self) -> Box<dyn Expr> {
+        self.read_token(); // eat {
+        let mut exprs = vec![];
+        while self.lexer.identifier_str != "}" {
+            exprs.push(self.parse_expr());
+            if self.lexer.identifier_str == ";" {
+                self.read_token(); // eat ;
+            } else if self.lexer.identifier_str != "}" {
+                panic!(
+                    "Expected ';' or '}}' in block, found {}",
+                    self.lexer.identifier_str
+                );
+            }
+        }
+        self.read_token(); // eat }
+        log_verbose(String::from("Parsed block expression"));
+        Box::new(BlockExprAst::new(exprs))
+    }
+
+    // matchexpr ::= 'match' expr '{' matcharm (',' matcharm)* ','? '}'
+    // matcharm ::= pattern ('if' expr)? '=>' expr
+    // pattern ::= '_' | identifier | constant ('|' constant)*
+    // constant ::= '-'? number
+    fn parse_match_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat match
+        let value = self.parse_expr();
+        if self.lexer.identifier_str != "{" {
+            panic!("Expected '{{' after match value");
+        }
+        self.read_token(); // eat {
+
+        let mut arms = vec![];
+        while self.lexer.identifier_str != "}" {
+            let pattern = self.parse_pattern();
+            let mut guard = None;
+            if self.cur_token == Some(Token::If) {
+                self.read_token(); // eat if
+                guard = Some(self.parse_expr());
+            }
+            if self.lexer.identifier_str != "=>" {
+                panic!("Expected '=>' after pattern");
+            }
+            self.read_token(); // eat =>
+            let body = self.parse_expr();
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                body,
+            });
+
+            if self.lexer.identifier_str == "," {
+                self.read_token(); // eat ,
+            } else if self.lexer.identifier_str != "}" {
+                panic!(
+                    "Expected ',' or '}}' in match, found {}",
+                    self.lexer.identifier_str
+                );
+            }
+        }
+        self.read_token(); // eat }
+        if arms.is_empty() {
+            panic!("Expected an arm in match");
+        }
+        if !arms.iter().any(|arm| arm.is_catch_all()) {
+            println!(
+                "Warning at {}: match has no catch-all arm, unmatched values evaluate to 0",
+                pos
+            );
+        }
+        log_verbose(String::from("Parsed match expression"));
+        Box::new(MatchExprAst::new(value, arms, pos))
+    }
+
+    fn parse_pattern(&mut self) -> Pattern {
+        if self.cur_token == Some(Token::Identifier) {
+            let name = self.lexer.identifier_str.clone();
+            self.read_token(); // eat identifier
+            return Pattern::Binding(name);
+        }
+        if self.lexer.identifier_str == "_" {
+            self.read_token(); // eat _
+            return Pattern::Wildcard;
+        }
+        let mut constants = vec![];
+        loop {
+            let sign = if self.lexer.identifier_str == "-" {
+                self.read_token(); // eat -
+                -1.0
+            } else {
+                1.0
+            };
+            if self.cur_token != Some(Token::Number) {
+                panic!("Expected a number, identifier or '_' as pattern");
+            }
+            constants.push(sign * self.lexer.num_val);
+            self.read_token(); // eat number
+            if self.lexer.identifier_str != "|" {
+                break;
+            }
+            self.read_token(); // eat |
+        }
+        Pattern::Constants(constants)
     }
 
     // primary ::= numberexpr
+    // primary ::= stringexpr
     // primary ::= identifierexpr
     // primary ::= parenthesisexpr
+    // primary ::= ifexpr
+    // primary ::= forexpr
+    // primary ::= varexpr
+    // primary ::= whileexpr
+    // primary ::= loopcontrolexpr
+    // primary ::= blockexpr
+    // primary ::= arrayexpr
+    // primary ::= lambda
+    // primary ::= matchexpr
     fn parse_primary(&mut self) -> Box<dyn Expr> {
         if let Some(tok) = &self.cur_token {
             match tok {
                 Token::Number => Box::new(self.parse_number_expr()),
+                Token::String => self.parse_string_expr(),
                 Token::Identifier => self.parse_identifier_expr(),
+                Token::If => self.parse_if_expr(),
+                Token::For => self.parse_for_expr(),
+                Token::Var => self.parse_var_expr(),
+                Token::While => self.parse_while_expr(),
+                Token::Break | Token::Continue => self.parse_loop_control_expr(),
+                Token::Match => self.parse_match_expr(),
                 Token::Character => match self.lexer.identifier_str.as_str() {
                     "(" => self.parse_parenthesis_expr(),
+                    "{" => self.parse_block_expr(),
+                    "[" => self.parse_array_expr(),
+                    "\\" => self.parse_lambda_expr(),
                     _ => panic!("Unexpected character {:?}", self.lexer.identifier_str),
                 },
                 _ => panic!("Unexpected token {:?}", tok),
@@ -92,9 +520,88 @@ impl<'a> Parser<'a> {
         }
     }
 
-    // binoprhs ::= (('+'|'-'|'*'|'/') primary)*
+    // lambda ::= '\' param* '->' expr
+    fn parse_lambda_expr(&mut self) -> Box<dyn Expr> {
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat \
+        let mut params = vec![];
+        let mut param_types = vec![];
+        while self.cur_token == Some(Token::Identifier) {
+            params.push(self.lexer.identifier_str.clone());
+            self.read_token(); // eat identifier
+            param_types.push(self.parse_type_annotation());
+            if self.lexer.identifier_str == "," {
+                self.read_token(); // eat ,
+            }
+        }
+        if self.lexer.identifier_str != "->" {
+            panic!("Expected '->' in lambda");
+        }
+        self.read_token(); // eat ->
+        let body = self.parse_expr();
+        log_verbose(String::from("Parsed lambda expression"));
+        Box::new(LambdaExprAst::new(params, param_types, body, pos))
+    }
+
+    // postfix ::= primary ('[' expr ']' | '.' identifier | '.' number)*
+    fn parse_postfix(&mut self) -> Box<dyn Expr> {
+        let mut expr = self.parse_primary();
+        while self.cur_token == Some(Token::Character) {
+            let pos = self.lexer.token_pos;
+            match self.lexer.identifier_str.as_str() {
+                "[" => {
+                    self.read_token(); // eat [
+                    let index = self.parse_expr();
+                    if self.lexer.identifier_str != "]" {
+                        panic!("Expected ']' after index");
+                    }
+                    self.read_token(); // eat ]
+                    log_verbose(String::from("Parsed index expression"));
+                    expr = Box::new(IndexExprAst::new(expr, index, pos));
+                }
+                "." => {
+                    self.read_token(); // eat .
+                    if self.cur_token == Some(Token::Number) {
+                        let index = self.lexer.num_val;
+                        if index.fract() != 0.0 {
+                            panic!("Expected tuple element index after '.', found {}", index);
+                        }
+                        self.read_token(); // eat number
+                        log_verbose(String::from("Parsed tuple element expression"));
+                        expr = Box::new(TupleIndexExprAst::new(expr, index as usize, None, pos));
+                        continue;
+                    }
+                    if self.cur_token != Some(Token::Identifier) {
+                        panic!("Expected field name after '.'");
+                    }
+                    let field = self.lexer.identifier_str.clone();
+                    self.read_token(); // eat identifier
+                    log_verbose(String::from("Parsed field expression"));
+                    expr = Box::new(FieldExprAst::new(expr, field, pos));
+                }
+                _ => break,
+            }
+        }
+        expr
+    }
+
+    // unary ::= postfix
+    // unary ::= operator unary
+    fn parse_unary(&mut self) -> Box<dyn Expr> {
+        if self.cur_token != Some(Token::Operator) {
+            return self.parse_postfix();
+        }
+
+        let op = self.lexer.identifier_str.clone();
+        let pos = self.lexer.token_pos;
+        self.read_token(); // eat operator
+        let operand = self.parse_unary();
+        log_verbose(format!("Parsed unary expression {}", op));
+        Box::new(UnaryExprAst::new(op, operand, pos))
+    }
+
+    // binoprhs ::= (binop unary)*
     fn parse_binary_op_rhs(&mut self, expr_precedence: i8, lhs: Box<dyn Expr>) -> Box<dyn Expr> {
-        // TODO left-right associativity
         let mut lhs = lhs;
         loop {
             let tok_precedence: i8 = self.get_op_precedence();
@@ -105,32 +612,130 @@ impl<'a> Parser<'a> {
 
             // found bin op
             let bin_op_char = self.lexer.identifier_str.clone();
+            let pos = self.lexer.token_pos;
             self.read_token(); // eat operator
 
-            let mut rhs = self.parse_primary();
+            let mut rhs = self.parse_unary();
 
+            // assignment is right-associative, x = y = 1 assigns 1 to y and then y to x
+            let is_right_assoc = bin_op_char == "=";
             let next_precedence: i8 = self.get_op_precedence();
-            if tok_precedence < next_precedence {
-                rhs = self.parse_binary_op_rhs(tok_precedence, rhs);
+            if tok_precedence < next_precedence
+                || (is_right_assoc && tok_precedence == next_precedence)
+            {
+                rhs = self.parse_binary_op_rhs(tok_precedence - is_right_assoc as i8, rhs);
             }
-            lhs = Box::new(BinaryExprAst::new(bin_op_char, lhs, rhs));
+            lhs = match bin_op_char.as_str() {
+                "&&" | "||" => Box::new(LogicalExprAst::new(bin_op_char, lhs, rhs, pos)),
+                _ => Box::new(BinaryExprAst::new(bin_op_char, lhs, rhs, pos)),
+            };
         }
     }
 
-    // expr ::= primary binoprhs
+    // expr ::= unary binoprhs
     fn parse_expr(&mut self) -> Box<dyn Expr> {
-        let lhs = self.parse_primary();
+        let lhs = self.parse_unary();
         self.parse_binary_op_rhs(0, lhs)
     }
 
-    // prototype ::= identifier '(' identifier* ')'
-    fn parse_prototype(&mut self) -> PrototypeAst {
+    // type ::= 'double' | 'int' | 'bool' | 'array' | qualifiedname
+    // type ::= '(' (type (',' type)*)? ')' '->' type
+    // type ::= '(' type (',' type)+ ')'
+    fn parse_type(&mut self) -> Type {
+        if self.cur_token == Some(Token::Character) && self.lexer.identifier_str == "(" {
+            // function or tuple type
+            self.read_token(); // eat (
+            let mut types = vec![];
+            while self.lexer.identifier_str != ")" {
+                types.push(self.parse_type());
+                if self.lexer.identifier_str == "," {
+                    self.read_token(); // eat ,
+                } else if self.lexer.identifier_str != ")" {
+                    panic!("Expected ',' or ')' in type");
+                }
+            }
+            self.read_token(); // eat )
+            if self.lexer.identifier_str != "->" {
+                if types.len() < 2 {
+                    panic!("Expected '->' in function type");
+                }
+                return Type::Tuple(types);
+            }
+            self.read_token(); // eat ->
+            return Type::Function(types, Box::new(self.parse_type()));
+        }
         if self.cur_token != Some(Token::Identifier) {
-            panic!("Expected identifier in prototype");
+            panic!("Expected type name");
+        }
+        let name = self.parse_qualified_name();
+        match Type::from_name(&name) {
+            Some(ty) => ty,
+            None if self.struct_names.contains(&name) => Type::Struct(name),
+            None => panic!("Unknown type {}", name),
         }
+    }
 
-        let name = self.lexer.identifier_str.clone();
-        self.read_token(); // eat identifier
+    // typeannotation ::= (':' type)?
+    fn parse_type_annotation(&mut self) -> Option<Type> {
+        if self.cur_token == Some(Token::Operator) && self.lexer.identifier_str == ":" {
+            self.read_token(); // eat :
+            Some(self.parse_type())
+        } else {
+            None
+        }
+    }
+
+    // prototype ::= identifier '(' param* '...'? ')' typeannotation
+    // prototype ::= 'binary' operator number? '(' param param ')' typeannotation
+    // prototype ::= 'unary' operator '(' param ')' typeannotation
+    // param ::= identifier typeannotation
+    fn parse_prototype(&mut self) -> PrototypeAst {
+        let mut binary_op = None;
+        let mut unary_op = None;
+        let pos = self.lexer.token_pos;
+        let name = match self.cur_token {
+            Some(Token::Identifier) => {
+                let name = self.lexer.identifier_str.clone();
+                self.read_token(); // eat identifier
+                name
+            }
+            Some(Token::Binary) => {
+                self.read_token(); // eat binary
+                let op = self.lexer.identifier_str.clone();
+                if self.cur_token != Some(Token::Operator) {
+                    panic!("Expected operator after 'binary', found {}", op);
+                }
+                self.read_token(); // eat operator
+
+                let mut precedence = DEFAULT_BINARY_OP_PRECEDENCE;
+                if self.cur_token == Some(Token::Number) {
+                    let value = self.lexer.num_val;
+                    if value < 1.0 || value > MAX_BINARY_OP_PRECEDENCE as f64 {
+                        panic!(
+                            "Invalid precedence {}, must be between 1 and {}",
+                            value, MAX_BINARY_OP_PRECEDENCE
+                        );
+                    }
+                    precedence = value as i8;
+                    self.read_token(); // eat precedence
+                }
+                let name = format!("binary{}", op);
+                binary_op = Some((op, precedence));
+                name
+            }
+            Some(Token::Unary) => {
+                self.read_token(); // eat unary
+                let op = self.lexer.identifier_str.clone();
+                if self.cur_token != Some(Token::Operator) {
+                    panic!("Expected operator after 'unary', found {}", op);
+                }
+                self.read_token(); // eat operator
+                let name = format!("unary{}", op);
+                unary_op = Some(op);
+                name
+            }
+            _ => panic!("Expected identifier in prototype"),
+        };
         if self.lexer.identifier_str != "(" {
             panic!("Expected '(' in prototype");
         }
@@ -138,13 +743,24 @@ impl<'a> Parser<'a> {
         self.read_token(); // eat (
 
         let mut args: Vec<String> = vec![];
+        let mut arg_types: Vec<Option<Type>> = vec![];
         while self.cur_token == Some(Token::Identifier) {
             args.push(self.lexer.identifier_str.clone());
             self.read_token(); // eat identifier
+            arg_types.push(self.parse_type_annotation());
             if self.lexer.identifier_str == "," {
                 self.read_token(); // eat ,
             }
         }
+        let is_variadic = self.lexer.identifier_str == ".";
+        if is_variadic {
+            for _ in 0..3 {
+                if self.lexer.identifier_str != "." {
+                    panic!("Expected '...' in prototype");
+                }
+                self.read_token(); // eat .
+            }
+        }
 
         if self.lexer.identifier_str != ")" {
             panic!(
@@ -154,13 +770,31 @@ impl<'a> Parser<'a> {
         }
 
         self.read_token(); // eat )
-        PrototypeAst::new(name, args)
+        let return_type = self.parse_type_annotation();
+        if let Some((op, precedence)) = binary_op {
+            if args.len() != 2 {
+                panic!("Invalid number of operands for binary operator {}", op);
+            }
+            // register the operator so that following expressions can use it
+            self.op_precedence.insert(op, precedence);
+        }
+        if let Some(op) = unary_op
+            && args.len() != 1
+        {
+            panic!("Invalid number of operands for unary operator {}", op);
+        }
+        let mut proto = PrototypeAst::new(name, args, arg_types, return_type, pos);
+        proto.is_variadic = is_variadic;
+        proto
     }
 
     // definition ::= 'def' prototype expr
     fn parse_def(&mut self) -> FunctionAst {
         self.read_token(); // eat def
         let proto = self.parse_prototype();
+        if proto.is_variadic {
+            panic!("Only externs can take variable arguments");
+        }
         let body = self.parse_expr();
         log_verbose(format!("Parsed function definition {}", proto.name));
         FunctionAst::new(proto, body)
@@ -173,16 +807,90 @@ impl<'a> Parser<'a> {
         self.parse_prototype()
     }
 
+    // global ::= ('global' | 'const') identifier '=' expr
+    fn parse_global(&mut self) -> GlobalVariableAst {
+        let is_constant = self.cur_token == Some(Token::Const);
+        self.read_token(); // eat global or const
+        if self.cur_token != Some(Token::Identifier) {
+            panic!("Expected identifier in global declaration");
+        }
+        let name = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+
+        if self.lexer.identifier_str != "=" {
+            panic!("Expected '=' after global {}", name);
+        }
+        self.read_token(); // eat =
+        let init = self.parse_expr();
+
+        log_verbose(format!("Parsed global declaration {}", name));
+        GlobalVariableAst::new(name, init, is_constant)
+    }
+
+    // struct ::= 'struct' identifier '{' (identifier typeannotation ','?)* '}'
+    fn parse_struct(&mut self) -> StructAst {
+        self.read_token(); // eat struct
+        if self.cur_token != Some(Token::Identifier) {
+            panic!("Expected identifier in struct declaration");
+        }
+        let name = self.lexer.identifier_str.clone();
+        self.read_token(); // eat identifier
+
+        if self.lexer.identifier_str != "{" {
+            panic!("Expected '{{' after struct {}", name);
+        }
+        self.read_token(); // eat {
+
+        let mut fields: Vec<(String, Type)> = vec![];
+        while self.cur_token == Some(Token::Identifier) {
+            let field = self.lexer.identifier_str.clone();
+            self.read_token(); // eat identifier
+            if fields.iter().any(|(other, _)| *other == field) {
+                panic!("Duplicate field {} in struct {}", field, name);
+            }
+            // struct fields are not inferred
+            let ty = self.parse_type_annotation().unwrap_or(Type::Double);
+            fields.push((field, ty));
+            if self.lexer.identifier_str == "," {
+                self.read_token(); // eat ,
+            }
+        }
+
+        if self.lexer.identifier_str != "}" {
+            panic!(
+                "Expected '}}' in struct declaration, found {}",
+                self.lexer.identifier_str
+            );
+        }
+        self.read_token(); // eat }
+
+        // register the struct so that following prototypes can use it as a type
+        self.struct_names.insert(name.clone());
+        log_verbose(format!("Parsed struct declaration {}", name));
+        StructAst::new(name, fields)
+    }
+
     // toplevelexpr ::= expr
     fn parse_top_level_expr(&mut self) -> FunctionAst {
+        let pos = self.lexer.token_pos;
         let expr = self.parse_expr();
-        let proto = PrototypeAst::new(String::new(), vec![]);
+        let proto = PrototypeAst::new(String::new(), vec![], vec![], Some(Type::Double), pos);
         log_verbose(String::from("Parsed top-level expression"));
         FunctionAst::new(proto, expr)
     }
 
     fn read_token(&mut self) {
         self.cur_token = Some(self.lexer.get_token());
+        self.is_malformed = self.cur_token == Some(Token::Error);
+        if self.is_malformed {
+            // the malformed literal is read as an empty one of its kind, so that the item can
+            // be parsed to its end
+            println!("{}", self.lexer.identifier_str);
+            self.lexer_errors.push(self.lexer.identifier_str.clone());
+            self.cur_token = Some(self.lexer.malformed_token.clone());
+            self.lexer.identifier_str = String::new();
+            self.lexer.string_val = String::new();
+        }
         log_verbose(format!(
             "Read a token {:?} {:?}",
             if !self.lexer.identifier_str.is_empty() {
@@ -195,23 +903,141 @@ impl<'a> Parser<'a> {
     }
 
     fn get_op_precedence(&self) -> i8 {
-        if self.cur_token != Some(Token::Character) {
+        if self.cur_token != Some(Token::Operator) {
             return -1;
         }
-        match self.lexer.identifier_str.as_str() {
-            "+" => 10,
-            "-" => 10,
-            "*" => 20,
-            "/" => 20,
-            _ => -1,
+        match self.op_precedence.get(&self.lexer.identifier_str) {
+            Some(precedence) => *precedence,
+            None => -1,
         }
     }
 
-    pub fn main_loop(&mut self) {
+    // import ::= 'import' (string | identifier)
+    // an identifier names the file identifier.k
+    fn parse_import(&mut self) -> PathBuf {
+        self.read_token(); // eat import
+        let file = match self.cur_token {
+            Some(Token::String) => self.lexer.string_val.clone(),
+            Some(Token::Identifier) => format!("{}.k", self.lexer.identifier_str),
+            _ => panic!("Expected file name after 'import'"),
+        };
+        self.read_token(); // eat file name
+        log_verbose(format!("Parsed import {}", file));
+        self.directory.join(file)
+    }
+
+    // compiles the imported module and declares its structs and functions under their
+    // qualified names
+    fn import_module(
+        &mut self,
+        path: &Path,
+        options: &CodeGenOptions,
+        modules: &mut Modules,
+    ) -> Result<Vec<Box<dyn Function>>, String> {
+        let exports = modules.import(path, options)?;
+        if self.module_names.contains(&exports.name) {
+            return Ok(vec![]);
+        }
+        let pos = self.lexer.token_pos;
+        let mut items: Vec<Box<dyn Function>> = vec![];
+        // structs are declared before the structs and functions using them
+        let mut structs = exports.structs.clone();
+        while !structs.is_empty() {
+            let count = structs.len();
+            structs.retain(|(name, fields)| {
+                let is_declared = |ty: &Type| match ty {
+                    Type::Struct(field_struct) => self.struct_names.contains(field_struct),
+                    _ => true,
+                };
+                if self.struct_names.contains(name) {
+                    return false;
+                }
+                if !fields.iter().all(|(_, ty)| is_declared(ty)) {
+                    return true;
+                }
+                self.struct_names.insert(name.clone());
+                items.push(Box::new(StructAst::new(name.clone(), fields.clone())));
+                false
+            });
+            if structs.len() == count {
+                return Err(format!("Cannot declare the structs of {}", exports.name));
+            }
+        }
+        for (name, params, ret) in exports.functions.iter() {
+            let args = (0..params.len()).map(|i| format!("arg{}", i)).collect();
+            let arg_types = params.iter().cloned().map(Some).collect();
+            items.push(Box::new(PrototypeAst::new(
+                name.clone(),
+                args,
+                arg_types,
+                Some(ret.clone()),
+                pos,
+            )));
+        }
+        self.module_names.insert(exports.name);
+        Ok(items)
+    }
+
+    // number of malformed literals before the current token, which is read with the item
+    // before it but belongs to the next one
+    fn item_errors(&self) -> usize {
+        self.lexer_errors.len() - self.is_malformed as usize
+    }
+
+    // parses the items of the buffer, imports are compiled right away, items with malformed
+    // literals are dropped
+    fn parse_items(
+        &mut self,
+        options: &CodeGenOptions,
+        modules: &mut Modules,
+    ) -> Vec<Box<dyn Function>> {
+        let mut items: Vec<Box<dyn Function>> = vec![];
+        loop {
+            let errors_before = self.item_errors();
+            if let Some(tok) = &self.cur_token {
+                let item: Box<dyn Function> = match tok {
+                    Token::Eof => break,
+                    Token::Def => Box::new(self.parse_def()),
+                    Token::Extern => Box::new(self.parse_extern()),
+                    Token::Global | Token::Const => Box::new(self.parse_global()),
+                    Token::Struct => Box::new(self.parse_struct()),
+                    Token::Import => {
+                        let path = self.parse_import();
+                        if self.item_errors() > errors_before {
+                            continue;
+                        }
+                        match self.import_module(&path, options, modules) {
+                            Ok(imported) => items.extend(imported),
+                            Err(e) => println!("{}", e),
+                        }
+                        continue;
+                    }
+                    Token::Character => match self.lexer.identifier_str.as_str() {
+                        ";" => {
+                            self.read_token(); // eat ;
+                            continue;
+                        }
+                        _ => Box::new(self.parse_top_level_expr()),
+                    },
+                    _ => Box::new(self.parse_top_level_expr()),
+                };
+                if self.item_errors() > errors_before {
+                    continue;
+                }
+                items.push(item);
+            } else {
+                panic!("Expected token");
+            }
+        }
+        items
+    }
+
+    pub fn main_loop(&mut self, options: CodeGenOptions) {
         // Note: run with echo <input string> | cargo run
         let stdin = stdin();
         let mut stdout = stdout();
-        let mut codegen_context = create_context();
+        let mut modules = Modules::default();
+        let mut items = vec![];
         loop {
             print!("ready>");
             stdout.flush().unwrap();
@@ -228,31 +1054,965 @@ impl<'a> Parser<'a> {
                 }
             }
 
-            loop {
-                if let Some(tok) = &self.cur_token {
-                    let function: Box<dyn Function> = match tok {
-                        Token::Eof => break,
-                        Token::Def => Box::new(self.parse_def()),
-                        Token::Extern => Box::new(self.parse_extern()),
-                        Token::Character => match self.lexer.identifier_str.as_str() {
-                            ";" => {
-                                self.read_token(); // eat ;
-                                continue;
-                            }
-                            _ => Box::new(self.parse_top_level_expr()),
-                        },
-                        _ => Box::new(self.parse_top_level_expr()),
-                    };
+            items.extend(self.parse_items(&options, &mut modules));
+        }
+
+        // the items are compiled once the whole program is read, so that functions can be
+        // used before their definition
+        let mut codegen_context = create_context(options, "module");
+        compile_items(items, &mut codegen_context, &mut TypeEnv::default());
+        run_passes(&mut codegen_context);
+        dump(&mut codegen_context);
+        emit_to_file(&mut codegen_context, "output.o");
+        dispose_context(&mut codegen_context);
+    }
+}
 
-                    generate_code(&mut codegen_context, function);
-                } else {
-                    dispose_context(&mut codegen_context);
-                    panic!("Expected token");
+// Tarjan's algorithm, the groups of items that depend on each other ordered so that every
+// group comes after the groups it depends on
+fn strongly_connected_components(dependencies: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct State<'a> {
+        dependencies: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        components: Vec<Vec<usize>>,
+        next_index: usize,
+    }
+    fn visit(state: &mut State, node: usize) {
+        state.index[node] = Some(state.next_index);
+        state.low_link[node] = state.next_index;
+        state.next_index += 1;
+        state.stack.push(node);
+        state.on_stack[node] = true;
+        for &dependency in state.dependencies[node].iter() {
+            match state.index[dependency] {
+                None => {
+                    visit(state, dependency);
+                    state.low_link[node] = state.low_link[node].min(state.low_link[dependency]);
                 }
+                Some(index) if state.on_stack[dependency] => {
+                    state.low_link[node] = state.low_link[node].min(index);
+                }
+                _ => {}
             }
         }
-        dump(&mut codegen_context);
-        emit_to_file(&mut codegen_context);
-        dispose_context(&mut codegen_context);
+        if Some(state.low_link[node]) == state.index[node] {
+            let mut component = vec![];
+            while let Some(member) = state.stack.pop() {
+                state.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            component.sort();
+            state.components.push(component);
+        }
+    }
+
+    let count = dependencies.len();
+    let mut state = State {
+        dependencies,
+        index: vec![None; count],
+        low_link: vec![0; count],
+        stack: vec![],
+        on_stack: vec![false; count],
+        components: vec![],
+        next_index: 0,
+    };
+    for node in 0..count {
+        if state.index[node].is_none() {
+            visit(&mut state, node);
+        }
+    }
+    state.components
+}
+
+// infers the types of the items and generates their code. Definitions and globals are declared
+// first and inferred after the definitions and globals they use. Structs and externs are
+// generated first, then the prototypes of all definitions, the globals and the bodies.
+pub fn compile_items(
+    mut items: Vec<Box<dyn Function>>,
+    codegen_context: &mut CodeGenContext,
+    type_env: &mut TypeEnv,
+) {
+    let mut is_valid = vec![true; items.len()];
+    let mut definitions: HashMap<String, usize> = HashMap::new();
+    let mut globals: HashMap<String, usize> = HashMap::new();
+    for (i, item) in items.iter_mut().enumerate() {
+        if let Some(global) = item.as_global() {
+            // a redefinition is rejected by the code generation
+            if !globals.contains_key(&global.name) {
+                globals.insert(global.name.clone(), i);
+                type_env.declared_globals.insert(global.name.clone());
+            }
+            continue;
+        }
+        let Some(definition) = item.as_definition() else {
+            continue;
+        };
+        let proto = &definition.proto;
+        // top-level expressions have no name
+        if proto.name.is_empty() {
+            continue;
+        }
+        if definitions.contains_key(&proto.name) {
+            println!("Redefinition of function {}", proto.name);
+            is_valid[i] = false;
+            continue;
+        }
+        definitions.insert(proto.name.clone(), i);
+        type_env.declared.insert(
+            proto.name.clone(),
+            (proto.arg_types.clone(), proto.return_type.clone()),
+        );
+    }
+
+    // structs and externs
+    for (i, item) in items.iter_mut().enumerate() {
+        if item.as_definition().is_none()
+            && item.as_global().is_none()
+            && let Err(e) = item.infer_types(type_env)
+        {
+            println!("{}", e);
+            is_valid[i] = false;
+        }
+    }
+
+    // definitions and globals, a group of definitions calling each other is inferred together
+    let dependencies: Vec<Vec<usize>> = items
+        .iter_mut()
+        .enumerate()
+        .map(|(i, item)| {
+            let references = if !is_valid[i] {
+                HashSet::new()
+            } else if let Some(definition) = item.as_definition() {
+                definition.references(type_env)
+            } else if let Some(global) = item.as_global() {
+                global.references(type_env)
+            } else {
+                HashSet::new()
+            };
+            references
+                .iter()
+                .filter_map(|name| definitions.get(name).or(globals.get(name)).copied())
+                .filter(|dependency| *dependency != i)
+                .collect()
+        })
+        .collect();
+    let mut inferred_globals = vec![];
+    for component in strongly_connected_components(&dependencies) {
+        // the type of a global is needed by the definitions using it, so it cannot depend on them
+        if component.len() > 1 {
+            let mut cyclic_globals = items
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| component.contains(i))
+                .filter_map(|(_, item)| item.as_global())
+                .peekable();
+            if cyclic_globals.peek().is_some() {
+                for global in cyclic_globals {
+                    println!("Initializer of {} depends on itself", global.name);
+                }
+                for i in component {
+                    is_valid[i] = false;
+                }
+                continue;
+            }
+        }
+
+        for (i, item) in items.iter_mut().enumerate() {
+            if !component.contains(&i) || !is_valid[i] {
+                continue;
+            }
+            if let Some(global) = item.as_global() {
+                match global.infer_types(type_env) {
+                    Ok(()) => inferred_globals.push(i),
+                    Err(e) => {
+                        println!("{}", e);
+                        is_valid[i] = false;
+                    }
+                }
+            }
+        }
+        let mut group: Vec<&mut FunctionAst> = items
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| component.contains(i) && is_valid[*i])
+            .filter_map(|(_, item)| item.as_definition())
+            .collect();
+        if group.is_empty() {
+            continue;
+        }
+        if let Err(e) = FunctionAst::infer_group(&mut group, type_env) {
+            println!("{}", e);
+            for i in component {
+                is_valid[i] = false;
+            }
+        }
+    }
+
+    for (i, item) in items.iter_mut().enumerate() {
+        if is_valid[i] && item.as_definition().is_none() && item.as_global().is_none() {
+            generate_code(codegen_context, item.as_ref());
+        }
+    }
+    // prototypes of the definitions before the globals using them and before any body
+    let mut functions = vec![None; items.len()];
+    for (i, item) in items.iter_mut().enumerate() {
+        if !is_valid[i] {
+            continue;
+        }
+        if let Some(definition) = item.as_definition() {
+            match definition.proto.generate_code(codegen_context) {
+                Ok(function) => functions[i] = Some(function),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+    // globals after the globals they use
+    for i in inferred_globals {
+        generate_code(codegen_context, items[i].as_ref());
+    }
+    for (item, function) in items.iter_mut().zip(functions) {
+        if let (Some(definition), Some(function)) = (item.as_definition(), function) {
+            log_verbose("===Start generate code===".to_string());
+            if let Err(e) = definition.generate_body(codegen_context, function) {
+                println!("{}", e);
+            }
+            log_verbose("===End generate code===".to_string());
+        }
+    }
+}
+
+// compiles an imported file into the object file of its module
+pub fn compile_module(
+    name: &str,
+    source: String,
+    path: &Path,
+    modules: &mut Modules,
+    options: CodeGenOptions,
+) -> ModuleExports {
+    log_verbose(format!("Compile module {}", name));
+    let mut lexer = Lexer::new();
+    lexer.set_buffer(source);
+    let mut parser = Parser::new(&mut lexer);
+    if let Some(directory) = path.parent() {
+        parser.directory = directory.to_path_buf();
+    }
+    parser.read_token();
+    let items = parser.parse_items(&options, modules);
+
+    let mut codegen_context = create_context(options, name);
+    let mut type_env = TypeEnv::default();
+    compile_items(items, &mut codegen_context, &mut type_env);
+    let exports = export_module(&mut codegen_context, &type_env, name);
+    run_passes(&mut codegen_context);
+    dump(&mut codegen_context);
+    emit_to_file(&mut codegen_context, &format!("{}.o", name));
+    dispose_context(&mut codegen_context);
+    exports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llvm_sys::core::*;
+    use llvm_sys::execution_engine::*;
+
+    // compiles the source and runs the function of the given name that takes and returns
+    // a double in the interpreter of LLVM
+    fn run_function(source: &str, name: &str, args: &[f64]) -> Vec<f64> {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(source));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let options = CodeGenOptions {
+            strip_asserts: false,
+        };
+        let items = parser.parse_items(&options, &mut Modules::default());
+        let mut codegen_context = create_context(options, "test");
+        compile_items(items, &mut codegen_context, &mut TypeEnv::default());
+        unsafe {
+            let name = (name.to_string() + "\0").into_bytes();
+            let function = LLVMGetNamedFunction(codegen_context.module, name.as_ptr() as *const i8);
+            assert!(!function.is_null());
+            LLVMLinkInInterpreter();
+            let mut engine = std::ptr::null_mut();
+            let mut error = std::ptr::null_mut();
+            // the engine owns the module
+            assert_eq!(
+                LLVMCreateInterpreterForModule(&mut engine, codegen_context.module, &mut error),
+                0
+            );
+            let dt = LLVMDoubleTypeInContext(codegen_context.context);
+            let results = args
+                .iter()
+                .map(|arg| {
+                    let mut args_v = [LLVMCreateGenericValueOfFloat(dt, *arg)];
+                    let result = LLVMRunFunction(engine, function, 1, args_v.as_mut_ptr());
+                    LLVMDisposeGenericValue(args_v[0]);
+                    let value = LLVMGenericValueToFloat(dt, result);
+                    LLVMDisposeGenericValue(result);
+                    value
+                })
+                .collect();
+            LLVMDisposeExecutionEngine(engine);
+            LLVMDisposeBuilder(codegen_context.ir_builder);
+            LLVMContextDispose(codegen_context.context);
+            results
+        }
+    }
+
+    #[test]
+    fn test_parse_binary_operator_definition() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("def binary| 5 (a b) a"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        // the definition replaces the precedence of the built-in operator
+        assert_eq!(parser.op_precedence.get("|"), Some(&6));
+
+        let function = parser.parse_def();
+        assert_eq!(function.proto.name, "binary|");
+        assert_eq!(parser.op_precedence.get("|"), Some(&5));
+    }
+
+    #[test]
+    fn test_parse_string_punctuation() {
+        // strings that look like punctuation are expressions
+        for input in [r#"println(")")"#, r#"{ "}" }"#, r#"[ "]", "," ]"#] {
+            let mut lexer = Lexer::new();
+            lexer.set_buffer(String::from(input));
+            let mut parser = Parser::new(&mut lexer);
+            parser.read_token();
+            parser.parse_expr();
+            assert_eq!(parser.cur_token, Some(Token::Eof));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a number, identifier or '_' as pattern")]
+    fn test_parse_string_pattern() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(r#"match x { "_" => 1 }"#));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        parser.parse_expr();
+    }
+
+    #[test]
+    fn test_parse_unary_operator_definition() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("def unary!(v) -v"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let function = parser.parse_def();
+        assert_eq!(function.proto.name, "unary!");
+        assert_eq!(function.proto.args, vec!["v"]);
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_var_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("var x = 1, y in x = y = x + 1; 2"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+        assert_eq!(parser.cur_token, Some(Token::Character));
+        assert_eq!(parser.lexer.identifier_str, ";");
+
+        // x = (y = x + a)
+        let source = "def f(a) var x = 1, y in { x = y = x + a; x * 10 + y }";
+        assert_eq!(run_function(source, "f", &[2.0]), vec![33.0]);
+    }
+
+    #[test]
+    fn test_parse_logical_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("a || b && c | d == e"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), -1);
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), 4);
+        parser.read_token();
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), 5);
+
+        // x == 1 || (x == 2 && x == 3) and 0 && (n | 1)
+        let source =
+            "def g(n: int) 0 && n | 1 def f(x: double) (x == 1 || x == 2 && x == 3) * 10 + g(x)";
+        assert_eq!(
+            run_function(source, "f", &[1.0, 2.0, 3.0]),
+            vec![10.0, 0.0, 0.0]
+        );
+
+        // the right operand is only evaluated if the left one does not decide the result
+        let source = "global calls = 0; def hit(n) { calls = calls + n; 1 } def f(x) { calls = 0; x && hit(1); x || hit(10); calls }";
+        assert_eq!(run_function(source, "f", &[0.0, 1.0]), vec![10.0, 1.0]);
+    }
+
+    #[test]
+    fn test_parse_while_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "while x < 10 do if x then break else for i = 0, i < x in continue",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+        assert_eq!(parser.loop_depth, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "'break' outside of a loop")]
+    fn test_parse_break_outside_loop() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("(while 1 do 2) + break"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+    }
+
+    #[test]
+    fn test_parse_block_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("{ a = 1; { }; f(a); } + { b; c }; d"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+        assert_eq!(parser.lexer.identifier_str, ";");
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected ';' or '}' in block")]
+    fn test_parse_block_expr_missing_separator() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("{ a b }"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+    }
+
+    #[test]
+    fn test_parse_global() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("const EPS = 1 / 1000; global count = 0"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let constant = parser.parse_global();
+        assert_eq!(constant.name, "EPS");
+        assert!(constant.is_constant);
+        parser.read_token(); // eat ;
+
+        let global = parser.parse_global();
+        assert_eq!(global.name, "count");
+        assert!(!global.is_constant);
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_typed_prototype() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("extern sum(a: array, n): double"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let proto = parser.parse_extern();
+        assert_eq!(proto.args, vec!["a", "n"]);
+        assert_eq!(proto.arg_types, vec![Some(Type::Array), None]);
+        assert_eq!(proto.return_type, Some(Type::Double));
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_int_bool_prototype() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def f(x: int, y: double): bool x & 1 << 2 == y",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let function = parser.parse_def();
+        assert_eq!(
+            function.proto.arg_types,
+            vec![Some(Type::Int), Some(Type::Double)]
+        );
+        assert_eq!(function.proto.return_type, Some(Type::Bool));
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_index_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("a[i + 1][0] = [1, 2][len(a)]; b"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+        assert_eq!(parser.lexer.identifier_str, ";");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown type float")]
+    fn test_parse_unknown_type() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("extern f(x: float)"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_extern();
+    }
+
+    #[test]
+    fn test_parse_struct() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "struct Point { x, y: double } struct Line { from: Point, to: Point }",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let point = parser.parse_struct();
+        assert_eq!(point.name, "Point");
+        assert_eq!(
+            point.fields,
+            vec![
+                (String::from("x"), Type::Double),
+                (String::from("y"), Type::Double)
+            ]
+        );
+
+        let line = parser.parse_struct();
+        assert_eq!(
+            line.fields,
+            vec![
+                (String::from("from"), Type::Struct(String::from("Point"))),
+                (String::from("to"), Type::Struct(String::from("Point")))
+            ]
+        );
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_field_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("l.from.x = Point(1, 2).y + a[0].x; b"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+        assert_eq!(parser.lexer.identifier_str, ";");
+    }
+
+    #[test]
+    #[should_panic(expected = "assert expects a condition and an optional exit code")]
+    fn test_parse_assert_arguments() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("assert(x > 0, 2, 3)"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        parser.parse_expr();
+    }
+
+    #[test]
+    fn test_generate_assert_exit_code() {
+        // the exit code is only evaluated if the assert fails
+        let source = "global calls = 0; def code() { calls = calls + 1; 3 } def f(x) { calls = 0; assert(x == x, code()); calls }";
+        assert_eq!(run_function(source, "f", &[1.0]), vec![0.0]);
+    }
+
+    #[test]
+    fn test_parse_function_type() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("extern solve(f: (double, int) -> bool, x)"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let proto = parser.parse_extern();
+        assert_eq!(
+            proto.arg_types,
+            vec![
+                Some(Type::Function(
+                    vec![Type::Double, Type::Int],
+                    Box::new(Type::Bool)
+                )),
+                None
+            ]
+        );
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_qualified_names() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "import \"lib/geometry.k\" extern f(p: geometry.Point): geometry.Point",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        assert_eq!(parser.parse_import(), PathBuf::from("./lib/geometry.k"));
+        parser.module_names.insert(String::from("geometry"));
+        parser.struct_names.insert(String::from("geometry.Point"));
+        let proto = parser.parse_extern();
+        assert_eq!(
+            proto.arg_types,
+            vec![Some(Type::Struct(String::from("geometry.Point")))]
+        );
+        assert_eq!(
+            proto.return_type,
+            Some(Type::Struct(String::from("geometry.Point")))
+        );
+    }
+
+    #[test]
+    fn test_infer_types() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def bits(x, n) x & (1 << n) - 1 def first(a, i) if i < len(a) then a[i] else 0",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut bits = parser.parse_def();
+        bits.infer_types(&mut type_env).unwrap();
+        assert_eq!(bits.proto.arg_types, vec![Some(Type::Int), Some(Type::Int)]);
+        assert_eq!(bits.proto.return_type, Some(Type::Int));
+
+        let mut first = parser.parse_def();
+        first.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            first.proto.arg_types,
+            vec![Some(Type::Array), Some(Type::Double)]
+        );
+        assert_eq!(first.proto.return_type, Some(Type::Double));
+        assert!(type_env.functions.contains_key("bits"));
+    }
+
+    #[test]
+    fn test_infer_number_literals() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def scale(x, n: int) x * n def half(n: int) n / 2",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        // an unknown value combined with an int may still be a double
+        let mut scale = parser.parse_def();
+        scale.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            scale.proto.arg_types,
+            vec![Some(Type::Double), Some(Type::Int)]
+        );
+        assert_eq!(scale.proto.return_type, Some(Type::Double));
+
+        // only literals become ints
+        let mut half = parser.parse_def();
+        half.infer_types(&mut type_env).unwrap();
+        assert_eq!(half.proto.return_type, Some(Type::Int));
+    }
+
+    #[test]
+    fn test_infer_function_value() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def apply(f, x) f(x) + 1 def twice(n: int) apply(apply, n)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut apply = parser.parse_def();
+        apply.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            apply.proto.arg_types,
+            vec![
+                Some(Type::Function(vec![Type::Double], Box::new(Type::Double))),
+                Some(Type::Double)
+            ]
+        );
+
+        // apply does not take itself
+        let mut twice = parser.parse_def();
+        assert!(twice.infer_types(&mut type_env).is_err());
+    }
+
+    #[test]
+    fn test_infer_lambda() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def adder(k: int) \\x: int -> x + k def bad(a: array) \\x -> x + a",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut adder = parser.parse_def();
+        adder.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            adder.proto.return_type,
+            Some(Type::Function(vec![Type::Int], Box::new(Type::Int)))
+        );
+
+        // captured variables keep their type
+        let mut bad = parser.parse_def();
+        assert!(bad.infer_types(&mut type_env).is_err());
+    }
+
+    #[test]
+    fn test_infer_mutual_recursion() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def even(n) if n == 0 then 1 else odd(n - 1) def odd(n: int) if n == 0 then 0 else even(n - 1)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut even = parser.parse_def();
+        let mut odd = parser.parse_def();
+        for function in [&even, &odd] {
+            let proto = &function.proto;
+            type_env.declared.insert(
+                proto.name.clone(),
+                (proto.arg_types.clone(), proto.return_type.clone()),
+            );
+        }
+        assert!(even.references(&type_env).contains("odd"));
+        assert_eq!(
+            strongly_connected_components(&[vec![1], vec![0], vec![0]]),
+            vec![vec![0, 1], vec![2]]
+        );
+
+        FunctionAst::infer_group(&mut [&mut even, &mut odd], &mut type_env).unwrap();
+        assert_eq!(even.proto.arg_types, vec![Some(Type::Int)]);
+        assert_eq!(even.proto.return_type, Some(Type::Double));
+        assert!(type_env.functions.contains_key("odd"));
+    }
+
+    #[test]
+    fn test_compile_global_before_definition() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "global f = inc; def inc(n) n & 1 def g(x: double) f(3)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let options = CodeGenOptions {
+            strip_asserts: false,
+        };
+        let items = parser.parse_items(&options, &mut Modules::default());
+        let mut codegen_context = create_context(options, "test");
+        let mut type_env = TypeEnv::default();
+        compile_items(items, &mut codegen_context, &mut type_env);
+
+        // the global is inferred after the definition it refers to and generated after its
+        // prototype
+        assert_eq!(
+            type_env.globals["f"],
+            Term::Function(vec![Term::Int], Box::new(Term::Int))
+        );
+        unsafe {
+            let global = LLVMGetNamedGlobal(codegen_context.module, c"f".as_ptr());
+            assert!(!global.is_null());
+            let function = LLVMGetNamedFunction(codegen_context.module, c"g".as_ptr());
+            assert_eq!(LLVMCountBasicBlocks(function), 1);
+            LLVMDisposeModule(codegen_context.module);
+            LLVMDisposeBuilder(codegen_context.ir_builder);
+            LLVMContextDispose(codegen_context.context);
+        }
+    }
+
+    #[test]
+    fn test_mark_tail_calls() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def loop(n, acc) if n == 0 then acc else { var m = n - 1 in loop(m, acc + n) } def fib(n) fib(n - 1) + fib(n - 2)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let function = parser.parse_def();
+        assert!(function.body.mark_tail_calls("loop"));
+        let function = parser.parse_def();
+        assert!(!function.body.mark_tail_calls("fib"));
+    }
+
+    #[test]
+    fn test_parse_match_expr() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def f(x: int) match x { 0 => 1, 1 | -2 => 3, n if n < 0 => n, _ => 2, }",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let mut function = parser.parse_def();
+        function.infer_types(&mut TypeEnv::default()).unwrap();
+        assert_eq!(function.proto.return_type, Some(Type::Int));
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_generate_match_switch() {
+        // arms after a catch-all arm are never matched, like in the comparison chain
+        let source =
+            "def first(x: int): double match x { _ => 1, 0 => 2 } def f(y: double) first(y)";
+        assert_eq!(run_function(source, "f", &[0.0, 3.0]), vec![1.0, 1.0]);
+
+        // an unannotated value is a double, which is switched on if it is integral
+        let source = "def g(x): double match x { 0 => 1, 2 | -3 => 2, n => n * 10 }";
+        assert_eq!(
+            run_function(source, "g", &[0.0, 2.0, -3.0, 2.5, 1e300]),
+            vec![1.0, 2.0, 2.0, 25.0, 1e301]
+        );
+        assert!(run_function(source, "g", &[f64::NAN])[0].is_nan());
+
+        // constants out of the range of ints are compared as doubles
+        let source = "def h(x: int): double match x { 3000000000 => 1, n if n > 0 => 2, _ => 3 } def f(y: double) h(y)";
+        assert_eq!(
+            run_function(source, "f", &[-1294967296.0, 5.0]),
+            vec![3.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_tuple_index() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def f(t: (double, (int, double))) t.1.1 + t.0",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let mut function = parser.parse_def();
+        function.infer_types(&mut TypeEnv::default()).unwrap();
+        assert_eq!(function.proto.return_type, Some(Type::Double));
+        assert_eq!(parser.cur_token, Some(Token::Eof));
+    }
+
+    #[test]
+    fn test_parse_malformed_literals() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def f(x) x + 1.2.3\ndef g(x) x * 2\n\"a\\q\"\n1.5",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        // the items with malformed literals are dropped, also if one directly follows an item
+        let items = parser.parse_items(
+            &CodeGenOptions {
+                strip_asserts: false,
+            },
+            &mut Modules::default(),
+        );
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            parser.lexer_errors,
+            vec![
+                "Lexer error at line 1, column 14: Malformed number literal \"1.2.3\"",
+                "Lexer error at line 3, column 1: Unknown escape sequence \\q in string literal",
+            ]
+        );
+
+        // and compiled
+        let source = "def f(x) x + 1.2.3 def g(x) x * 2";
+        assert_eq!(run_function(source, "g", &[2.0]), vec![4.0]);
+    }
+
+    #[test]
+    fn test_infer_tuple() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "def minmax(a, b: int) (a - b, b) def swap(p) var (a, b) = p in (b, a & 1)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut minmax = parser.parse_def();
+        minmax.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            minmax.proto.return_type,
+            Some(Type::Tuple(vec![Type::Double, Type::Int]))
+        );
+
+        // the elements of a destructured tuple are inferred like variables
+        let mut swap = parser.parse_def();
+        swap.infer_types(&mut type_env).unwrap();
+        assert_eq!(
+            swap.proto.arg_types,
+            vec![Some(Type::Tuple(vec![Type::Int, Type::Double]))]
+        );
+    }
+
+    #[test]
+    fn test_infer_variadic_extern() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from(
+            "extern printf(fmt: string, ...): int def show(x) printf(\"%d %f\", x & 1, x)",
+        ));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        let mut type_env = TypeEnv::default();
+
+        let mut printf = parser.parse_extern();
+        assert!(printf.is_variadic);
+        assert_eq!(printf.arg_types, vec![Some(Type::String)]);
+        printf.infer_types(&mut type_env).unwrap();
+
+        // the variable arguments are inferred from their uses
+        let mut show = parser.parse_def();
+        show.infer_types(&mut type_env).unwrap();
+        assert_eq!(show.proto.arg_types, vec![Some(Type::Int)]);
+    }
+
+    #[test]
+    fn test_infer_type_error() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("def f(a)\n  a[0] + len(a) + a.x"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+
+        let mut function = parser.parse_def();
+        assert_eq!(
+            function.infer_types(&mut TypeEnv::default()),
+            Err(String::from(
+                "Type error at line 2, column 20: Cannot access field x of a value of type array"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_get_op_precedence() {
+        let mut lexer = Lexer::new();
+        lexer.set_buffer(String::from("a <= b ; c"));
+        let mut parser = Parser::new(&mut lexer);
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), -1);
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), 8);
+        parser.read_token();
+        parser.read_token();
+        assert_eq!(parser.get_op_precedence(), -1);
     }
 }
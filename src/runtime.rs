@@ -0,0 +1,505 @@
+extern crate llvm_sys as llvm;
+use crate::{codegen::*, logger::*};
+use llvm::core::*;
+use llvm::prelude::LLVMTypeRef;
+use llvm::prelude::LLVMValueRef;
+use llvm::{LLVMIntPredicate, LLVMLinkage};
+
+// The runtime functions are generated into the module on first use, so that emitted
+// objects only depend on the C standard library.
+
+const PRINT_NUMBER: &str = "kalei_print_number";
+const PRINT_INT: &str = "kalei_print_int";
+const PRINT_STRING: &str = "kalei_print_string";
+const ASSERT_FAIL: &str = "kalei_assert_fail";
+const INDEX_FAIL: &str = "kalei_index_fail";
+
+// returns the function of the given name or adds it with the body built by build_body
+fn get_or_create_function(
+    codegen_context: &mut CodeGenContext,
+    name: &str,
+    ft: LLVMTypeRef,
+    build_body: fn(&mut CodeGenContext, LLVMValueRef),
+) -> LLVMValueRef {
+    unsafe {
+        let c_name = (name.to_string() + "\0").into_bytes();
+        let ptr = c_name.as_ptr() as *const i8;
+        let function = LLVMGetNamedFunction(codegen_context.module, ptr);
+        if !function.is_null() {
+            return function;
+        }
+
+        log_verbose(format!("Generate runtime function {:?}", name));
+        let function = LLVMAddFunction(codegen_context.module, ptr, ft);
+        LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+
+        // the body is built with the builder of the context, restore its position afterwards
+        let insert_bb = LLVMGetInsertBlock(codegen_context.ir_builder);
+        let entry_bb =
+            LLVMAppendBasicBlockInContext(codegen_context.context, function, c"entry".as_ptr());
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, entry_bb);
+        build_body(codegen_context, function);
+        if insert_bb.is_null() {
+            LLVMClearInsertionPosition(codegen_context.ir_builder);
+        } else {
+            LLVMPositionBuilderAtEnd(codegen_context.ir_builder, insert_bb);
+        }
+        function
+    }
+}
+
+// declares the external C function of the given name if it does not exist yet
+fn get_or_declare_function(
+    codegen_context: &mut CodeGenContext,
+    name: &str,
+    ft: LLVMTypeRef,
+) -> LLVMValueRef {
+    unsafe {
+        let c_name = (name.to_string() + "\0").into_bytes();
+        let ptr = c_name.as_ptr() as *const i8;
+        let function = LLVMGetNamedFunction(codegen_context.module, ptr);
+        if !function.is_null() {
+            return function;
+        }
+        LLVMAddFunction(codegen_context.module, ptr, ft)
+    }
+}
+
+// int printf(const char *format, ...)
+fn get_printf(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let mut params_t = [string_type(codegen_context)];
+        let ft = LLVMFunctionType(
+            LLVMInt32TypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            true as i32,
+        );
+        get_or_declare_function(codegen_context, "printf", ft)
+    }
+}
+
+// void *calloc(size_t num, size_t size)
+fn get_calloc(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let size_t = LLVMInt64TypeInContext(codegen_context.context);
+        let mut params_t = [size_t, size_t];
+        let ft = LLVMFunctionType(
+            string_type(codegen_context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_declare_function(codegen_context, "calloc", ft)
+    }
+}
+
+// void exit(int status)
+fn get_exit(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let mut params_t = [LLVMInt32TypeInContext(codegen_context.context)];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_declare_function(codegen_context, "exit", ft)
+    }
+}
+
+fn build_printf(codegen_context: &mut CodeGenContext, format: &str, values: &[LLVMValueRef]) {
+    unsafe {
+        let printf = get_printf(codegen_context);
+        let mut args_v = vec![create_global_string(codegen_context, format)];
+        args_v.extend_from_slice(values);
+        LLVMBuildCall2(
+            codegen_context.ir_builder,
+            LLVMGlobalGetValueType(printf),
+            printf,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            c"".as_ptr(),
+        );
+    }
+}
+
+// void kalei_print_number(double value)
+fn get_print_number(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let mut params_t = [LLVMDoubleTypeInContext(codegen_context.context)];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_create_function(codegen_context, PRINT_NUMBER, ft, |ctx, function| {
+            build_printf(ctx, "%g", &[LLVMGetParam(function, 0)]);
+            LLVMBuildRetVoid(ctx.ir_builder);
+        })
+    }
+}
+
+// void kalei_print_int(int value)
+fn get_print_int(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let mut params_t = [int_type(codegen_context)];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_create_function(codegen_context, PRINT_INT, ft, |ctx, function| {
+            build_printf(ctx, "%d", &[LLVMGetParam(function, 0)]);
+            LLVMBuildRetVoid(ctx.ir_builder);
+        })
+    }
+}
+
+// void kalei_print_string(const char *value)
+fn get_print_string(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let mut params_t = [string_type(codegen_context)];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_create_function(codegen_context, PRINT_STRING, ft, |ctx, function| {
+            build_printf(ctx, "%s", &[LLVMGetParam(function, 0)]);
+            LLVMBuildRetVoid(ctx.ir_builder);
+        })
+    }
+}
+
+// void kalei_assert_fail(const char *function, int line, int status)
+// reports the failed assertion and exits with the status, or 1 if the status is 0
+fn get_assert_fail(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let i32_t = LLVMInt32TypeInContext(codegen_context.context);
+        let mut params_t = [string_type(codegen_context), i32_t, i32_t];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_create_function(codegen_context, ASSERT_FAIL, ft, |ctx, function| {
+            build_printf(
+                ctx,
+                "Assertion failed in %s at line %d\n",
+                &[LLVMGetParam(function, 0), LLVMGetParam(function, 1)],
+            );
+            let i32_t = LLVMInt32TypeInContext(ctx.context);
+            let status = LLVMGetParam(function, 2);
+            let is_zero = LLVMBuildICmp(
+                ctx.ir_builder,
+                LLVMIntPredicate::LLVMIntEQ,
+                status,
+                LLVMConstInt(i32_t, 0, false as i32),
+                c"iszero".as_ptr(),
+            );
+            let mut args_v = [LLVMBuildSelect(
+                ctx.ir_builder,
+                is_zero,
+                LLVMConstInt(i32_t, 1, false as i32),
+                status,
+                c"status".as_ptr(),
+            )];
+            let exit = get_exit(ctx);
+            LLVMBuildCall2(
+                ctx.ir_builder,
+                LLVMGlobalGetValueType(exit),
+                exit,
+                args_v.as_mut_ptr(),
+                args_v.len() as u32,
+                c"".as_ptr(),
+            );
+            LLVMBuildUnreachable(ctx.ir_builder);
+        })
+    }
+}
+
+// void kalei_index_fail(const char *function, int line, long index, long len)
+// reports the index that is out of the bounds of the array and exits with status 1
+fn get_index_fail(codegen_context: &mut CodeGenContext) -> LLVMValueRef {
+    unsafe {
+        let i32_t = LLVMInt32TypeInContext(codegen_context.context);
+        let i64_t = LLVMInt64TypeInContext(codegen_context.context);
+        let mut params_t = [string_type(codegen_context), i32_t, i64_t, i64_t];
+        let ft = LLVMFunctionType(
+            LLVMVoidTypeInContext(codegen_context.context),
+            params_t.as_mut_ptr(),
+            params_t.len() as u32,
+            false as i32,
+        );
+        get_or_create_function(codegen_context, INDEX_FAIL, ft, |ctx, function| {
+            build_printf(
+                ctx,
+                "Index %ld out of bounds of an array of length %ld in %s at line %d\n",
+                &[
+                    LLVMGetParam(function, 2),
+                    LLVMGetParam(function, 3),
+                    LLVMGetParam(function, 0),
+                    LLVMGetParam(function, 1),
+                ],
+            );
+            let i32_t = LLVMInt32TypeInContext(ctx.context);
+            let mut args_v = [LLVMConstInt(i32_t, 1, false as i32)];
+            let exit = get_exit(ctx);
+            LLVMBuildCall2(
+                ctx.ir_builder,
+                LLVMGlobalGetValueType(exit),
+                exit,
+                args_v.as_mut_ptr(),
+                args_v.len() as u32,
+                c"".as_ptr(),
+            );
+            LLVMBuildUnreachable(ctx.ir_builder);
+        })
+    }
+}
+
+// the name of the prototype of the enclosing function, for runtime failure reports
+fn enclosing_function_name(codegen_context: &CodeGenContext) -> String {
+    let function = codegen_context.current_function.unwrap();
+    match value_name(function) {
+        name if name.is_empty() => String::from("top-level expression"),
+        name => name,
+    }
+}
+
+// branches to the failure report if the condition is false, the i32 status is generated by
+// build_status in the failure branch
+pub fn build_assert(
+    codegen_context: &mut CodeGenContext,
+    cond: LLVMValueRef,
+    line: u32,
+    build_status: impl FnOnce(&mut CodeGenContext) -> Result<LLVMValueRef, String>,
+) -> Result<(), String> {
+    unsafe {
+        let function_name = enclosing_function_name(codegen_context);
+        log_verbose(format!(
+            "Generate assert in {:?} at line {}",
+            function_name, line
+        ));
+        let function = codegen_context.current_function.unwrap();
+        let fail_bb = LLVMAppendBasicBlockInContext(
+            codegen_context.context,
+            function,
+            c"assertfail".as_ptr(),
+        );
+        let cont_bb = LLVMAppendBasicBlockInContext(
+            codegen_context.context,
+            function,
+            c"assertcont".as_ptr(),
+        );
+        LLVMBuildCondBr(codegen_context.ir_builder, cond, cont_bb, fail_bb);
+
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, fail_bb);
+        let status = build_status(codegen_context)?;
+        let assert_fail = get_assert_fail(codegen_context);
+        let mut args_v = [
+            create_global_string(codegen_context, &function_name),
+            LLVMConstInt(
+                LLVMInt32TypeInContext(codegen_context.context),
+                line as u64,
+                false as i32,
+            ),
+            status,
+        ];
+        LLVMBuildCall2(
+            codegen_context.ir_builder,
+            LLVMGlobalGetValueType(assert_fail),
+            assert_fail,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            c"".as_ptr(),
+        );
+        LLVMBuildUnreachable(codegen_context.ir_builder);
+
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, cont_bb);
+        Ok(())
+    }
+}
+
+// branches to the failure report if the index is not below the length, both are i64,
+// negative indexes are out of bounds as they are compared unsigned
+pub fn build_bounds_check(
+    codegen_context: &mut CodeGenContext,
+    index: LLVMValueRef,
+    len: LLVMValueRef,
+    line: u32,
+) {
+    unsafe {
+        let function_name = enclosing_function_name(codegen_context);
+        log_verbose(format!(
+            "Generate bounds check in {:?} at line {}",
+            function_name, line
+        ));
+        let in_bounds = LLVMBuildICmp(
+            codegen_context.ir_builder,
+            LLVMIntPredicate::LLVMIntULT,
+            index,
+            len,
+            c"inbounds".as_ptr(),
+        );
+        let function = codegen_context.current_function.unwrap();
+        let fail_bb =
+            LLVMAppendBasicBlockInContext(codegen_context.context, function, c"indexfail".as_ptr());
+        let cont_bb =
+            LLVMAppendBasicBlockInContext(codegen_context.context, function, c"indexcont".as_ptr());
+        LLVMBuildCondBr(codegen_context.ir_builder, in_bounds, cont_bb, fail_bb);
+
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, fail_bb);
+        let index_fail = get_index_fail(codegen_context);
+        let mut args_v = [
+            create_global_string(codegen_context, &function_name),
+            LLVMConstInt(
+                LLVMInt32TypeInContext(codegen_context.context),
+                line as u64,
+                false as i32,
+            ),
+            index,
+            len,
+        ];
+        LLVMBuildCall2(
+            codegen_context.ir_builder,
+            LLVMGlobalGetValueType(index_fail),
+            index_fail,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            c"".as_ptr(),
+        );
+        LLVMBuildUnreachable(codegen_context.ir_builder);
+
+        LLVMPositionBuilderAtEnd(codegen_context.ir_builder, cont_bb);
+    }
+}
+
+// print and println, which print their arguments separated by spaces and evaluate to 0.0
+pub fn build_print(
+    codegen_context: &mut CodeGenContext,
+    args: &[LLVMValueRef],
+    newline: bool,
+) -> Result<LLVMValueRef, String> {
+    unsafe {
+        log_verbose(format!("Generate print of {} values", args.len()));
+        for (i, arg) in args.iter().enumerate() {
+            let arg_t = LLVMTypeOf(*arg);
+            let mut arg = *arg;
+            let function = if is_double(arg) {
+                get_print_number(codegen_context)
+            } else if is_number(arg) {
+                // bools are printed as 0 or 1
+                let it = int_type(codegen_context);
+                arg = build_conversion(codegen_context, arg, it).unwrap();
+                get_print_int(codegen_context)
+            } else if arg_t == string_type(codegen_context) {
+                get_print_string(codegen_context)
+            } else {
+                return Err(format!("Cannot print a value of type {}", type_name(arg_t)));
+            };
+
+            if i > 0 {
+                let print_string = get_print_string(codegen_context);
+                let mut separator_v = [create_global_string(codegen_context, " ")];
+                LLVMBuildCall2(
+                    codegen_context.ir_builder,
+                    LLVMGlobalGetValueType(print_string),
+                    print_string,
+                    separator_v.as_mut_ptr(),
+                    1,
+                    c"".as_ptr(),
+                );
+            }
+            let mut args_v = [arg];
+            LLVMBuildCall2(
+                codegen_context.ir_builder,
+                LLVMGlobalGetValueType(function),
+                function,
+                args_v.as_mut_ptr(),
+                1,
+                c"".as_ptr(),
+            );
+        }
+
+        if newline {
+            let print_string = get_print_string(codegen_context);
+            let mut args_v = [create_global_string(codegen_context, "\n")];
+            LLVMBuildCall2(
+                codegen_context.ir_builder,
+                LLVMGlobalGetValueType(print_string),
+                print_string,
+                args_v.as_mut_ptr(),
+                1,
+                c"".as_ptr(),
+            );
+        }
+        Ok(LLVMConstReal(
+            LLVMDoubleTypeInContext(codegen_context.context),
+            0.0,
+        ))
+    }
+}
+
+// zeroed heap memory for a value of the given type, like arrays it is never freed
+pub fn build_alloc(codegen_context: &mut CodeGenContext, ty: LLVMTypeRef) -> LLVMValueRef {
+    unsafe {
+        log_verbose("Generate allocation".to_string());
+        let calloc = get_calloc(codegen_context);
+        let size_t = LLVMInt64TypeInContext(codegen_context.context);
+        let mut args_v = [LLVMConstInt(size_t, 1, false as i32), LLVMSizeOf(ty)];
+        LLVMBuildCall2(
+            codegen_context.ir_builder,
+            LLVMGlobalGetValueType(calloc),
+            calloc,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            c"memory".as_ptr(),
+        )
+    }
+}
+
+// allocates a zero-initialized array of len doubles on the heap, len is an i64
+pub fn build_array_alloc(codegen_context: &mut CodeGenContext, len: LLVMValueRef) -> LLVMValueRef {
+    unsafe {
+        log_verbose("Generate array allocation".to_string());
+        let calloc = get_calloc(codegen_context);
+        let double_t = LLVMDoubleTypeInContext(codegen_context.context);
+        let mut args_v = [len, LLVMSizeOf(double_t)];
+        let memory = LLVMBuildCall2(
+            codegen_context.ir_builder,
+            LLVMGlobalGetValueType(calloc),
+            calloc,
+            args_v.as_mut_ptr(),
+            args_v.len() as u32,
+            c"memory".as_ptr(),
+        );
+        let data = LLVMBuildPointerCast(
+            codegen_context.ir_builder,
+            memory,
+            LLVMPointerType(double_t, 0),
+            c"data".as_ptr(),
+        );
+
+        let array_t = array_type(codegen_context);
+        let array = LLVMBuildInsertValue(
+            codegen_context.ir_builder,
+            LLVMGetUndef(array_t),
+            len,
+            0,
+            c"array".as_ptr(),
+        );
+        LLVMBuildInsertValue(
+            codegen_context.ir_builder,
+            array,
+            data,
+            1,
+            c"array".as_ptr(),
+        )
+    }
+}
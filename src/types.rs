@@ -0,0 +1,70 @@
+extern crate llvm_sys as llvm;
+use crate::{closure::*, codegen::*};
+use llvm::core::*;
+use llvm::prelude::LLVMTypeRef;
+
+// types of function parameters and return values
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+    Double,
+    Int,
+    Bool,
+    Array,
+    // pointer to a null-terminated string constant like const char * of C
+    String,
+    // struct declared by the program
+    Struct(String),
+    // closure with the parameter and return types
+    Function(Vec<Type>, Box<Type>),
+    // literal struct of the element types
+    Tuple(Vec<Type>),
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "double" => Some(Type::Double),
+            "int" => Some(Type::Int),
+            "bool" => Some(Type::Bool),
+            "array" => Some(Type::Array),
+            "string" => Some(Type::String),
+            _ => None,
+        }
+    }
+
+    pub fn llvm_type(&self, codegen_context: &CodeGenContext) -> Result<LLVMTypeRef, String> {
+        match self {
+            Type::Double => Ok(unsafe { LLVMDoubleTypeInContext(codegen_context.context) }),
+            Type::Int => Ok(int_type(codegen_context)),
+            Type::Bool => Ok(bool_type(codegen_context)),
+            Type::Array => Ok(array_type(codegen_context)),
+            Type::String => Ok(string_type(codegen_context)),
+            Type::Struct(name) => match codegen_context.struct_types.get(name) {
+                Some(definition) => Ok(definition.ty),
+                None => Err(format!("Unknown struct {}", name)),
+            },
+            Type::Function(params, ret) => {
+                let params_t = params
+                    .iter()
+                    .map(|ty| ty.llvm_type(codegen_context))
+                    .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+                let return_t = ret.llvm_type(codegen_context)?;
+                Ok(closure_type(codegen_context, &params_t, return_t))
+            }
+            Type::Tuple(elements) => {
+                let mut elements_t = elements
+                    .iter()
+                    .map(|ty| ty.llvm_type(codegen_context))
+                    .collect::<Result<Vec<LLVMTypeRef>, String>>()?;
+                Ok(unsafe {
+                    LLVMStructTypeInContext(
+                        codegen_context.context,
+                        elements_t.as_mut_ptr(),
+                        elements_t.len() as u32,
+                        false as i32,
+                    )
+                })
+            }
+        }
+    }
+}